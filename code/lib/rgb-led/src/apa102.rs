@@ -0,0 +1,112 @@
+use crate::chip::Chip;
+use crate::smart_led::{dim, SmartLed};
+use crate::{DEFAULT_LED_COUNT, MAX_LED_COUNT};
+use anyhow::{bail, Result};
+use esp_idf_hal::{
+    gpio::{AnyIOPin, AnyOutputPin, OutputPin},
+    peripheral::Peripheral,
+    spi::{config, SpiAnyPins, SpiDeviceDriver, SpiDriver, SpiDriverConfig},
+    units::FromValueType,
+};
+use rgb::RGB8;
+
+// Top 3 bits of every LED frame are set, the low 5 are the global brightness
+const LED_FRAME: u8 = 0xE0;
+const MAX_GLOBAL: u8 = 31;
+// The start frame, and the extra zeros SK9822s need to latch a frame
+const START_LEN: usize = 4;
+const LATCH_LEN: usize = 4;
+
+// APA102 and SK9822 LEDs, which take a clock alongside the data so can be
+// driven from SPI. Each LED also has a 5-bit global brightness that dims
+// without giving up color resolution.
+pub struct Apa102<'a> {
+    spi: SpiDeviceDriver<'a, SpiDriver<'a>>,
+    count: usize,
+    frame: Vec<u8>,
+}
+
+impl<'d> Apa102<'d> {
+    pub fn new<SPI: SpiAnyPins>(
+        spi: impl Peripheral<P = SPI> + 'd,
+        clock: impl Peripheral<P = impl OutputPin> + 'd,
+        data: impl Peripheral<P = impl OutputPin> + 'd,
+    ) -> Result<Self> {
+        let config = config::Config::new().baudrate(4.MHz().into());
+        let spi = SpiDeviceDriver::new_single(
+            spi,
+            clock,
+            data,
+            Option::<AnyIOPin>::None,
+            Option::<AnyOutputPin>::None,
+            &SpiDriverConfig::new(),
+            &config,
+        )?;
+
+        Ok(Self {
+            spi,
+            count: DEFAULT_LED_COUNT,
+            frame: Vec::with_capacity(frame_len(DEFAULT_LED_COUNT)),
+        })
+    }
+
+    fn write(&mut self, pixels: impl Iterator<Item = RGB8>, global: u8) -> Result<()> {
+        self.frame.clear();
+        self.frame.extend_from_slice(&[0; START_LEN]);
+        for rgb in pixels.take(self.count) {
+            self.frame
+                .extend_from_slice(&[LED_FRAME | global, rgb.b, rgb.g, rgb.r]);
+        }
+        // Data lags half a clock per LED, so keep clocking until it reaches the end
+        self.frame.resize(frame_len(self.count), 0);
+        self.spi.write(&self.frame)?;
+
+        Ok(())
+    }
+}
+
+impl<'d> SmartLed for Apa102<'d> {
+    fn count(&self) -> usize {
+        self.count
+    }
+
+    fn set_count(&mut self, count: usize) {
+        self.count = count.min(MAX_LED_COUNT);
+        self.frame = Vec::with_capacity(frame_len(self.count));
+    }
+
+    fn set_pixels(&mut self, pixels: &[RGB8]) -> Result<()> {
+        self.set_pixels_dimmed(pixels, 1.0)
+    }
+
+    // Use the smallest global brightness that reaches `drive`, and make up the
+    // rest in the colors, so dim colors keep as many levels as bright ones
+    fn set_pixels_dimmed(&mut self, pixels: &[RGB8], drive: f32) -> Result<()> {
+        let drive = drive.clamp(0.0, 1.0);
+        let global = (drive * MAX_GLOBAL as f32).ceil() as u8;
+        let scale = if global == 0 {
+            0.0
+        } else {
+            drive * MAX_GLOBAL as f32 / global as f32
+        };
+
+        let pixels = pixels
+            .iter()
+            .map(|rgb| dim(rgb, scale))
+            .chain(core::iter::repeat(RGB8::default()));
+        self.write(pixels, global)
+    }
+
+    // The clocked chips all speak the same protocol, so there's nothing to
+    // switch. Refuse anything else rather than claim to be driving it.
+    fn set_chip(&mut self, chip: Chip) -> Result<()> {
+        if chip != Chip::default() {
+            bail!("APA102 LEDs can't be driven as {:?}", chip);
+        }
+        Ok(())
+    }
+}
+
+fn frame_len(count: usize) -> usize {
+    START_LEN + count * 4 + LATCH_LEN + count.div_ceil(16)
+}
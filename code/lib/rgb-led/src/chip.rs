@@ -0,0 +1,147 @@
+use rgb::RGB8;
+use serde::{Deserialize, Serialize};
+
+// Bit timings, in ns
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Timing {
+    pub t0h: u64,
+    pub t0l: u64,
+    pub t1h: u64,
+    pub t1l: u64,
+    // Low time that latches a frame, so the next one starts over from the first LED
+    pub reset: u64,
+}
+
+// Order the channels go out on the wire
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ColorOrder {
+    Rgb,
+    Grb,
+    Grbw,
+}
+
+impl ColorOrder {
+    pub fn channels(self) -> usize {
+        match self {
+            ColorOrder::Rgb | ColorOrder::Grb => 3,
+            ColorOrder::Grbw => 4,
+        }
+    }
+
+    // Channel values in wire order. Only the first `channels()` are used.
+    pub fn encode(self, rgb: RGB8) -> [u8; 4] {
+        match self {
+            ColorOrder::Rgb => [rgb.r, rgb.g, rgb.b, 0],
+            ColorOrder::Grb => [rgb.g, rgb.r, rgb.b, 0],
+            ColorOrder::Grbw => {
+                let (rgb, w) = extract_white(rgb);
+                [rgb.g, rgb.r, rgb.b, w]
+            }
+        }
+    }
+}
+
+// The LED chips we know how to drive
+#[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Chip {
+    #[default]
+    Ws2812,
+    // 800 kHz mode, usually wired RGB
+    Ws2811,
+    Sk6812,
+    // Separate white LED, which gives warmer and cleaner whites than mixing
+    Sk6812Rgbw,
+}
+
+impl Chip {
+    pub fn timing(self) -> Timing {
+        match self {
+            Chip::Ws2812 => Timing {
+                t0h: 350,
+                t0l: 800,
+                t1h: 700,
+                t1l: 600,
+                // Newer WS2812B revisions need far more than the 50 us in the
+                // original datasheet
+                reset: 300_000,
+            },
+            Chip::Ws2811 => Timing {
+                t0h: 250,
+                t0l: 1000,
+                t1h: 600,
+                t1l: 650,
+                reset: 60_000,
+            },
+            Chip::Sk6812 | Chip::Sk6812Rgbw => Timing {
+                t0h: 300,
+                t0l: 900,
+                t1h: 600,
+                t1l: 600,
+                reset: 80_000,
+            },
+        }
+    }
+
+    pub fn order(self) -> ColorOrder {
+        match self {
+            Chip::Ws2812 | Chip::Sk6812 => ColorOrder::Grb,
+            Chip::Ws2811 => ColorOrder::Rgb,
+            Chip::Sk6812Rgbw => ColorOrder::Grbw,
+        }
+    }
+
+    pub fn channels(self) -> usize {
+        self.order().channels()
+    }
+}
+
+// Move the part of a color all three channels share onto the white LED
+pub fn extract_white(rgb: RGB8) -> (RGB8, u8) {
+    let w = rgb.r.min(rgb.g).min(rgb.b);
+    (RGB8::new(rgb.r - w, rgb.g - w, rgb.b - w), w)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COLOR: RGB8 = RGB8 { r: 1, g: 2, b: 3 };
+
+    #[test]
+    fn encodes_in_wire_order() {
+        assert_eq!(ColorOrder::Rgb.encode(COLOR)[..3], [1, 2, 3]);
+        assert_eq!(ColorOrder::Grb.encode(COLOR)[..3], [2, 1, 3]);
+        assert_eq!(
+            ColorOrder::Grbw.encode(RGB8::new(10, 20, 30)),
+            [10, 0, 20, 10]
+        );
+    }
+
+    #[test]
+    fn each_chip_sends_its_channels() {
+        assert_eq!(Chip::Ws2812.order(), ColorOrder::Grb);
+        assert_eq!(Chip::Ws2811.order(), ColorOrder::Rgb);
+        assert_eq!(Chip::Sk6812.order(), ColorOrder::Grb);
+        assert_eq!(Chip::Sk6812Rgbw.channels(), 4);
+        assert_eq!(Chip::Ws2812.channels(), 3);
+    }
+
+    #[test]
+    fn moves_shared_light_onto_the_white_led() {
+        assert_eq!(
+            extract_white(RGB8::new(255, 255, 255)),
+            (RGB8::default(), 255)
+        );
+        assert_eq!(
+            extract_white(RGB8::new(200, 120, 40)),
+            (RGB8::new(160, 80, 0), 40)
+        );
+        // Nothing shared by all three, so the white LED stays off
+        assert_eq!(
+            extract_white(RGB8::new(255, 0, 128)),
+            (RGB8::new(255, 0, 128), 0)
+        );
+        assert_eq!(extract_white(RGB8::default()), (RGB8::default(), 0));
+    }
+}
@@ -1,50 +1,19 @@
-use anyhow::Result;
-use core::time::Duration;
-use esp_idf_hal::{
-    gpio::OutputPin,
-    peripheral::Peripheral,
-    rmt::{config::TransmitConfig, FixedLengthSignal, PinState, Pulse, RmtChannel, TxRmtDriver},
-};
+#[cfg(target_os = "espidf")]
+mod apa102;
+mod chip;
+mod smart_led;
+#[cfg(target_os = "espidf")]
+mod ws2812;
 
+#[cfg(target_os = "espidf")]
+pub use apa102::Apa102;
+pub use chip::{extract_white, Chip, ColorOrder, Timing};
 pub use rgb::RGB8;
+pub use smart_led::{dim, RecordingLed, SmartLed};
+#[cfg(target_os = "espidf")]
+pub use ws2812::WS2812RMT;
 
-pub struct WS2812RMT<'a> {
-    tx_rtm_driver: TxRmtDriver<'a>,
-}
-
-impl<'d> WS2812RMT<'d> {
-    // Rust ESP Board gpio2,  ESP32-C3-DevKitC-02 gpio8
-    pub fn new(
-        led: impl Peripheral<P = impl OutputPin> + 'd,
-        channel: impl Peripheral<P = impl RmtChannel> + 'd,
-    ) -> Result<Self> {
-        let config = TransmitConfig::new().clock_divider(2);
-        let tx = TxRmtDriver::new(channel, led, &config)?;
-        Ok(Self { tx_rtm_driver: tx })
-    }
-
-    pub fn set_pixel(&mut self, rgb: RGB8) -> Result<()> {
-        let color: u32 = ((rgb.g as u32) << 16) | ((rgb.r as u32) << 8) | rgb.b as u32;
-        let ticks_hz = self.tx_rtm_driver.counter_clock()?;
-        let t0h = Pulse::new_with_duration(ticks_hz, PinState::High, &ns(350))?;
-        let t0l = Pulse::new_with_duration(ticks_hz, PinState::Low, &ns(800))?;
-        let t1h = Pulse::new_with_duration(ticks_hz, PinState::High, &ns(700))?;
-        let t1l = Pulse::new_with_duration(ticks_hz, PinState::Low, &ns(600))?;
-        let mut signal = FixedLengthSignal::<192>::new();
-        for j in 0..8 {
-            for i in (0..24).rev() {
-                let p = 2_u32.pow(i);
-                let bit = p & color != 0;
-                let (high_pulse, low_pulse) = if bit { (t1h, t1l) } else { (t0h, t0l) };
-                signal.set(24 * j + (23 - i) as usize, &(high_pulse, low_pulse))?;
-            }
-        }
-        self.tx_rtm_driver.start_blocking(&signal)?;
-
-        Ok(())
-    }
-}
-
-fn ns(nanos: u64) -> Duration {
-    Duration::from_nanos(nanos)
-}
+// The lamp board has 8 LEDs, more can be chained on the extension terminal
+pub const DEFAULT_LED_COUNT: usize = 8;
+// Keeps the RMT signal buffer to a sane size
+pub const MAX_LED_COUNT: usize = 256;
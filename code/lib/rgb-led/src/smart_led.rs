@@ -0,0 +1,143 @@
+use crate::chip::Chip;
+use anyhow::Result;
+use rgb::RGB8;
+
+// A chain of individually addressable LEDs
+pub trait SmartLed {
+    // How many LEDs are on the chain
+    fn count(&self) -> usize;
+
+    fn set_count(&mut self, count: usize);
+
+    // One color per LED, from the start of the chain. LEDs past the end of
+    // `pixels` are turned off, and pixels past the end of the chain are ignored.
+    fn set_pixels(&mut self, pixels: &[RGB8]) -> Result<()>;
+
+    // Show `pixels` at `drive`, from 0 to 1, of their full intensity. Drivers
+    // with a hardware dimmer use it to keep color resolution at the low end,
+    // the rest scale the colors down.
+    fn set_pixels_dimmed(&mut self, pixels: &[RGB8], drive: f32) -> Result<()> {
+        let pixels: Vec<RGB8> = pixels.iter().map(|rgb| dim(rgb, drive)).collect();
+        self.set_pixels(&pixels)
+    }
+
+    // Every LED the same color
+    fn set_pixel(&mut self, rgb: RGB8) -> Result<()> {
+        let pixels = vec![rgb; self.count()];
+        self.set_pixels(&pixels)
+    }
+
+    // Make sure everything written so far has reached the LEDs. Drivers that
+    // write straight away have nothing to do.
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    // Drivers that only talk to one kind of chip ignore this
+    fn set_chip(&mut self, _chip: Chip) -> Result<()> {
+        Ok(())
+    }
+}
+
+pub fn dim(rgb: &RGB8, drive: f32) -> RGB8 {
+    let drive = drive.clamp(0.0, 1.0);
+    let channel = |c: u8| (c as f32 * drive).round() as u8;
+    RGB8::new(channel(rgb.r), channel(rgb.g), channel(rgb.b))
+}
+
+// Stands in for a real chain off the hardware, keeping every frame written
+// to it so lamp output can be checked in host tests
+#[derive(Debug, Clone, Default)]
+pub struct RecordingLed {
+    count: usize,
+    chip: Chip,
+    frames: Vec<Vec<RGB8>>,
+}
+
+impl RecordingLed {
+    pub fn new(count: usize) -> Self {
+        RecordingLed {
+            count,
+            ..Default::default()
+        }
+    }
+
+    // Oldest first, each exactly as long as the chain was when it was written
+    pub fn frames(&self) -> &[Vec<RGB8>] {
+        &self.frames
+    }
+
+    pub fn last_frame(&self) -> Option<&[RGB8]> {
+        self.frames.last().map(|frame| frame.as_slice())
+    }
+
+    pub fn chip(&self) -> Chip {
+        self.chip
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+}
+
+impl SmartLed for RecordingLed {
+    fn count(&self) -> usize {
+        self.count
+    }
+
+    fn set_count(&mut self, count: usize) {
+        self.count = count;
+    }
+
+    fn set_pixels(&mut self, pixels: &[RGB8]) -> Result<()> {
+        let mut frame = pixels.to_vec();
+        frame.resize(self.count, RGB8::default());
+        self.frames.push(frame);
+
+        Ok(())
+    }
+
+    fn set_chip(&mut self, chip: Chip) -> Result<()> {
+        self.chip = chip;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: RGB8 = RGB8 { r: 255, g: 0, b: 0 };
+
+    #[test]
+    fn frames_fit_the_chain() {
+        let mut led = RecordingLed::new(3);
+        led.set_pixels(&[RED]).unwrap();
+        led.set_pixels(&[RED; 5]).unwrap();
+
+        assert_eq!(
+            led.frames(),
+            [vec![RED, RGB8::default(), RGB8::default()], vec![RED; 3]]
+        );
+    }
+
+    #[test]
+    fn dims_by_scaling_the_colors() {
+        let mut led = RecordingLed::new(2);
+        led.set_pixels_dimmed(&[RED; 2], 0.5).unwrap();
+        assert_eq!(led.last_frame(), Some([RGB8::new(128, 0, 0); 2].as_slice()));
+
+        led.set_pixels_dimmed(&[RED; 2], 2.0).unwrap();
+        assert_eq!(led.last_frame(), Some([RED; 2].as_slice()));
+    }
+
+    #[test]
+    fn set_pixel_fills_the_chain() {
+        let mut led = RecordingLed::new(4);
+        led.set_pixel(RED).unwrap();
+        assert_eq!(led.last_frame(), Some([RED; 4].as_slice()));
+
+        led.clear();
+        assert!(led.frames().is_empty());
+    }
+}
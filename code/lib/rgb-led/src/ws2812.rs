@@ -0,0 +1,210 @@
+use crate::chip::{Chip, Timing};
+use crate::smart_led::SmartLed;
+use crate::{DEFAULT_LED_COUNT, MAX_LED_COUNT};
+use anyhow::Result;
+use core::time::Duration;
+use esp_idf_hal::{
+    delay::BLOCK,
+    gpio::OutputPin,
+    peripheral::Peripheral,
+    rmt::{config::TransmitConfig, PinState, Pulse, RmtChannel, Signal, Symbol, TxRmtDriver},
+    sys::{esp, rmt_item32_t, rmt_wait_tx_done, rmt_write_items},
+};
+use rgb::RGB8;
+
+// RMT items for each 4-bit value, most significant bit first. One item holds
+// the high and low halves of one bit.
+type NibbleTable = [[rmt_item32_t; 4]; 16];
+
+pub struct WS2812RMT<'a> {
+    tx_rtm_driver: TxRmtDriver<'a>,
+    count: usize,
+    chip: Chip,
+    table: NibbleTable,
+    // Low time after each frame
+    reset: rmt_item32_t,
+    // One frame can be filled in while the other is on the wire
+    buffers: [Vec<rmt_item32_t>; 2],
+    // Which buffer the RMT peripheral is reading from, if any
+    sending: Option<usize>,
+}
+
+impl<'d> WS2812RMT<'d> {
+    // Rust ESP Board gpio2,  ESP32-C3-DevKitC-02 gpio8
+    pub fn new(
+        led: impl Peripheral<P = impl OutputPin> + 'd,
+        channel: impl Peripheral<P = impl RmtChannel> + 'd,
+    ) -> Result<Self> {
+        Self::with_count(led, channel, DEFAULT_LED_COUNT)
+    }
+
+    pub fn with_count(
+        led: impl Peripheral<P = impl OutputPin> + 'd,
+        channel: impl Peripheral<P = impl RmtChannel> + 'd,
+        count: usize,
+    ) -> Result<Self> {
+        Self::with_chip(led, channel, count, Chip::default())
+    }
+
+    pub fn with_chip(
+        led: impl Peripheral<P = impl OutputPin> + 'd,
+        channel: impl Peripheral<P = impl RmtChannel> + 'd,
+        count: usize,
+        chip: Chip,
+    ) -> Result<Self> {
+        let count = count.min(MAX_LED_COUNT);
+        let config = TransmitConfig::new().clock_divider(2);
+        let tx = TxRmtDriver::new(channel, led, &config)?;
+        let (table, reset) = symbols(&tx, chip.timing())?;
+
+        Ok(Self {
+            tx_rtm_driver: tx,
+            count,
+            chip,
+            table,
+            reset,
+            buffers: [
+                Vec::with_capacity(frame_len(count, chip)),
+                Vec::with_capacity(frame_len(count, chip)),
+            ],
+            sending: None,
+        })
+    }
+
+    pub fn chip(&self) -> Chip {
+        self.chip
+    }
+
+    // Encode the frame into whichever buffer isn't on the wire, then send it as
+    // soon as the previous frame is done. Returns without waiting for it to go out.
+    fn write(&mut self, pixels: impl Iterator<Item = RGB8>) -> Result<()> {
+        let order = self.chip.order();
+        let next = self.sending.map_or(0, |idx| 1 - idx);
+        let buffer = &mut self.buffers[next];
+
+        buffer.clear();
+        for rgb in pixels.take(self.count) {
+            for channel in &order.encode(rgb)[..order.channels()] {
+                buffer.extend_from_slice(&self.table[(channel >> 4) as usize]);
+                buffer.extend_from_slice(&self.table[(channel & 0x0f) as usize]);
+            }
+        }
+        buffer.push(self.reset);
+
+        // The peripheral only takes one frame at a time
+        self.flush()?;
+
+        let buffer = &self.buffers[next];
+        // SAFETY: the buffer isn't touched again until the transmission is done,
+        // see flush()
+        esp!(unsafe {
+            rmt_write_items(
+                self.tx_rtm_driver.channel(),
+                buffer.as_ptr(),
+                buffer.len() as i32,
+                false,
+            )
+        })?;
+        self.sending = Some(next);
+
+        Ok(())
+    }
+
+    // Make room for a whole frame in both buffers
+    fn reserve(&mut self) -> Result<()> {
+        // Growing a buffer could move it out from under the peripheral
+        self.flush()?;
+        let len = frame_len(self.count, self.chip);
+        for buffer in &mut self.buffers {
+            buffer.reserve(len.saturating_sub(buffer.len()));
+        }
+
+        Ok(())
+    }
+}
+
+impl<'d> SmartLed for WS2812RMT<'d> {
+    fn count(&self) -> usize {
+        self.count
+    }
+
+    fn set_count(&mut self, count: usize) {
+        self.count = count.min(MAX_LED_COUNT);
+        if let Err(error) = self.reserve() {
+            log::warn!("LED frame didn't finish sending: {}", error);
+        }
+    }
+
+    fn set_pixels(&mut self, pixels: &[RGB8]) -> Result<()> {
+        self.write(
+            pixels
+                .iter()
+                .copied()
+                .chain(core::iter::repeat(RGB8::default())),
+        )
+    }
+
+    fn set_pixel(&mut self, rgb: RGB8) -> Result<()> {
+        self.write(core::iter::repeat(rgb))
+    }
+
+    // Wait for the frame on the wire to finish
+    fn flush(&mut self) -> Result<()> {
+        if self.sending.is_some() {
+            esp!(unsafe { rmt_wait_tx_done(self.tx_rtm_driver.channel(), BLOCK) })?;
+            self.sending = None;
+        }
+
+        Ok(())
+    }
+
+    fn set_chip(&mut self, chip: Chip) -> Result<()> {
+        let (table, reset) = symbols(&self.tx_rtm_driver, chip.timing())?;
+        self.chip = chip;
+        self.table = table;
+        self.reset = reset;
+        self.reserve()
+    }
+}
+
+impl<'d> Drop for WS2812RMT<'d> {
+    // The peripheral may still be reading from one of the buffers
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+// Items for every bit pattern, and the item that ends a frame
+fn symbols(tx: &TxRmtDriver, timing: Timing) -> Result<(NibbleTable, rmt_item32_t)> {
+    let ticks_hz = tx.counter_clock()?;
+    let pulse = |state, nanos| Pulse::new_with_duration(ticks_hz, state, &ns(nanos));
+
+    let zero = Symbol::new(
+        pulse(PinState::High, timing.t0h)?,
+        pulse(PinState::Low, timing.t0l)?,
+    );
+    let one = Symbol::new(
+        pulse(PinState::High, timing.t1h)?,
+        pulse(PinState::Low, timing.t1l)?,
+    );
+    // Every item has two halves, so the reset is split across both
+    let half_reset = pulse(PinState::Low, timing.reset / 2)?;
+    let reset = Symbol::new(half_reset, half_reset);
+
+    let zero = zero.as_slice()[0];
+    let one = one.as_slice()[0];
+    let table = core::array::from_fn(|nibble| {
+        core::array::from_fn(|bit| if nibble & (8 >> bit) != 0 { one } else { zero })
+    });
+
+    Ok((table, reset.as_slice()[0]))
+}
+
+// One item per bit, plus the reset
+fn frame_len(count: usize, chip: Chip) -> usize {
+    count * chip.channels() * 8 + 1
+}
+
+fn ns(nanos: u64) -> Duration {
+    Duration::from_nanos(nanos)
+}
@@ -0,0 +1,399 @@
+pub mod animation {
+    use crate::color::color::mix_oklab;
+    use rgb::RGB8;
+    use serde::{Deserialize, Serialize};
+    use std::f32::consts::PI;
+
+    // Breathing never goes fully dark, so the color stays recognisable
+    pub const BREATHE_FLOOR: f32 = 0.1;
+    // Fraction of each period a strobe flash is lit for
+    pub const STROBE_DUTY: f32 = 0.1;
+    // Anything faster than this is uncomfortable to look at
+    pub const MIN_PERIOD: u32 = 100;
+
+    #[derive(Debug, Copy, Clone, PartialEq)]
+    pub enum Effect {
+        Steady,
+        // Sinusoidal swell between BREATHE_FLOOR and full
+        Breathe,
+        // On for half of each period
+        Blink,
+        // Short flash at the start of each period
+        Strobe,
+        // Ramp up from dark over one period, then hold
+        FadeIn,
+        // Sinusoidal swing from the color towards the target color and back
+        Shift,
+    }
+
+    // Length of one cycle of each effect, in ms
+    #[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq)]
+    #[serde(default)]
+    pub struct AnimationPeriods {
+        pub breathe: u32,
+        pub blink: u32,
+        pub strobe: u32,
+        pub fade: u32,
+        // How long changes of color, brightness or power blend over. 0 snaps.
+        pub crossfade: u32,
+    }
+
+    impl AnimationPeriods {
+        pub fn clamped(&self) -> Self {
+            AnimationPeriods {
+                breathe: self.breathe.max(MIN_PERIOD),
+                blink: self.blink.max(MIN_PERIOD),
+                strobe: self.strobe.max(MIN_PERIOD),
+                fade: self.fade.max(MIN_PERIOD),
+                crossfade: self.crossfade,
+            }
+        }
+
+        fn of(&self, effect: Effect) -> Option<u32> {
+            match effect {
+                Effect::Steady => None,
+                Effect::Breathe => Some(self.breathe),
+                Effect::Blink => Some(self.blink),
+                Effect::Strobe => Some(self.strobe),
+                Effect::FadeIn => Some(self.fade),
+                Effect::Shift => Some(self.breathe),
+            }
+        }
+    }
+
+    // Periods as posted through the API, where any left out stay as they are
+    #[derive(Deserialize, Serialize, Debug, Default, Copy, Clone, PartialEq)]
+    pub struct AnimationPeriodsUpdate {
+        pub breathe: Option<u32>,
+        pub blink: Option<u32>,
+        pub strobe: Option<u32>,
+        pub fade: Option<u32>,
+        pub crossfade: Option<u32>,
+    }
+
+    impl AnimationPeriodsUpdate {
+        pub fn merged_into(&self, periods: AnimationPeriods) -> AnimationPeriods {
+            AnimationPeriods {
+                breathe: self.breathe.unwrap_or(periods.breathe),
+                blink: self.blink.unwrap_or(periods.blink),
+                strobe: self.strobe.unwrap_or(periods.strobe),
+                fade: self.fade.unwrap_or(periods.fade),
+                crossfade: self.crossfade.unwrap_or(periods.crossfade),
+            }
+        }
+    }
+
+    impl Default for AnimationPeriods {
+        fn default() -> Self {
+            AnimationPeriods {
+                breathe: 4000,
+                blink: 1000,
+                strobe: 2000,
+                fade: 1500,
+                crossfade: 1000,
+            }
+        }
+    }
+
+    // An effect applied to a color, started at a given time. Frames depend only on
+    // how long the animation has been running, never on how often they're asked for.
+    #[derive(Debug, Copy, Clone, PartialEq)]
+    pub struct Animation {
+        pub effect: Effect,
+        pub color: RGB8,
+        // Second color, for effects that move between two
+        pub target: RGB8,
+        // Overrides the period configured for the effect
+        pub period: Option<u32>,
+        // Uptime the animation started at, in ms
+        pub started: u64,
+    }
+
+    impl Animation {
+        pub fn new(effect: Effect, color: RGB8, started: u64) -> Self {
+            Animation {
+                effect,
+                color,
+                target: color,
+                period: None,
+                started,
+            }
+        }
+
+        pub fn with_period(mut self, period: u32) -> Self {
+            self.period = Some(period.max(MIN_PERIOD));
+            self
+        }
+
+        pub fn with_target(mut self, target: RGB8) -> Self {
+            self.target = target;
+            self
+        }
+
+        fn period(&self, periods: &AnimationPeriods) -> Option<u32> {
+            self.period.or(periods.of(self.effect))
+        }
+
+        // How far through the effect we are at `now`, from 0 to 1. For brightness
+        // effects this is how lit the color is, for Shift how close to the target.
+        pub fn level(&self, now: u64, periods: &AnimationPeriods) -> f32 {
+            let Some(period) = self.period(periods) else {
+                return 1.0;
+            };
+
+            let elapsed = now.saturating_sub(self.started);
+            let phase = (elapsed % period as u64) as f32 / period as f32;
+            let swell = 0.5 - 0.5 * (2.0 * PI * phase).cos();
+
+            match self.effect {
+                Effect::Steady => 1.0,
+                Effect::Breathe => BREATHE_FLOOR + (1.0 - BREATHE_FLOOR) * swell,
+                Effect::Blink => (phase < 0.5) as i32 as f32,
+                Effect::Strobe => (phase < STROBE_DUTY) as i32 as f32,
+                Effect::FadeIn => (elapsed as f32 / period as f32).min(1.0),
+                Effect::Shift => swell,
+            }
+        }
+
+        pub fn frame(&self, now: u64, periods: &AnimationPeriods) -> RGB8 {
+            let level = self.level(now, periods);
+
+            match self.effect {
+                Effect::Shift => mix_oklab(&self.color, &self.target, level),
+                _ => scale(&self.color, level),
+            }
+        }
+
+        // Whether the frame will never change again, so there's no need to keep rendering
+        pub fn is_settled(&self, now: u64, periods: &AnimationPeriods) -> bool {
+            match self.effect {
+                Effect::Steady => true,
+                Effect::FadeIn => self
+                    .period(periods)
+                    .is_some_and(|period| now.saturating_sub(self.started) >= period as u64),
+                Effect::Shift => self.color == self.target,
+                _ => false,
+            }
+        }
+    }
+
+    // Blends whatever was on the LED into whatever should be there now
+    #[derive(Debug, Copy, Clone, PartialEq)]
+    pub struct Crossfade {
+        pub from: RGB8,
+        // Brightness the LED was at, from 0 to 1
+        pub from_level: f32,
+        // Uptime the crossfade started at, in ms
+        pub started: u64,
+        pub duration: u32,
+    }
+
+    impl Crossfade {
+        pub fn new(from: RGB8, from_level: f32, started: u64, duration: u32) -> Self {
+            Crossfade {
+                from,
+                from_level,
+                started,
+                duration,
+            }
+        }
+
+        pub fn is_done(&self, now: u64) -> bool {
+            now.saturating_sub(self.started) >= self.duration as u64
+        }
+
+        // Ease in and out, so neither end of the fade is abrupt
+        fn progress(&self, now: u64) -> f32 {
+            if self.is_done(now) {
+                return 1.0;
+            }
+
+            let t = now.saturating_sub(self.started) as f32 / self.duration as f32;
+            t * t * (3.0 - 2.0 * t)
+        }
+
+        // `to` can keep changing while the crossfade runs, e.g. if it's animated
+        pub fn apply(&self, now: u64, to: &RGB8) -> RGB8 {
+            mix_oklab(&self.from, to, self.progress(now))
+        }
+
+        pub fn level(&self, now: u64, to: f32) -> f32 {
+            self.from_level + (to - self.from_level) * self.progress(now)
+        }
+    }
+
+    // Levels are perceptual, like the lamp brightness, so this scales the sRGB
+    // values and leaves the conversion to light for when the frame goes out
+    pub fn scale(color: &RGB8, level: f32) -> RGB8 {
+        let level = level.clamp(0.0, 1.0);
+        RGB8 {
+            r: (color.r as f32 * level).round() as u8,
+            g: (color.g as f32 * level).round() as u8,
+            b: (color.b as f32 * level).round() as u8,
+        }
+    }
+
+    // Frames `interval` ms apart, e.g. to compare an animation against a recording
+    pub fn record(
+        animation: &Animation,
+        periods: &AnimationPeriods,
+        interval: u64,
+        count: usize,
+    ) -> Vec<RGB8> {
+        (0..count as u64)
+            .map(|idx| animation.frame(animation.started + idx * interval, periods))
+            .collect()
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const RED: RGB8 = RGB8 { r: 255, g: 0, b: 0 };
+        const BLUE: RGB8 = RGB8 { r: 0, g: 0, b: 255 };
+        const BLACK: RGB8 = RGB8 { r: 0, g: 0, b: 0 };
+
+        fn red(r: u8) -> RGB8 {
+            RGB8 { r, g: 0, b: 0 }
+        }
+
+        #[test]
+        fn steady_never_changes() {
+            let periods = AnimationPeriods::default();
+            let steady = Animation::new(Effect::Steady, RED, 500);
+
+            assert_eq!(record(&steady, &periods, 700, 4), [RED; 4]);
+            assert!(steady.is_settled(500, &periods));
+        }
+
+        #[test]
+        fn breathe_swells_from_the_floor_and_back() {
+            let periods = AnimationPeriods::default();
+            let breathe = Animation::new(Effect::Breathe, RED, 1000);
+
+            // Quarter periods: floor, half way, full, half way, floor
+            assert_eq!(
+                record(&breathe, &periods, 1000, 5),
+                [red(26), red(140), red(255), red(140), red(26)]
+            );
+            assert!(!breathe.is_settled(1000, &periods));
+        }
+
+        #[test]
+        fn breathe_follows_its_own_period() {
+            let periods = AnimationPeriods::default();
+            let pulse = Animation::new(Effect::Breathe, RED, 0).with_period(1000);
+
+            assert_eq!(
+                record(&pulse, &periods, 250, 5),
+                [red(26), red(140), red(255), red(140), red(26)]
+            );
+        }
+
+        #[test]
+        fn blink_is_on_for_half_of_each_period() {
+            let periods = AnimationPeriods::default();
+            let blink = Animation::new(Effect::Blink, RED, 0);
+
+            assert_eq!(
+                record(&blink, &periods, 250, 6),
+                [RED, RED, BLACK, BLACK, RED, RED]
+            );
+        }
+
+        #[test]
+        fn strobe_flashes_at_the_start_of_each_period() {
+            let periods = AnimationPeriods::default();
+            let strobe = Animation::new(Effect::Strobe, RED, 0);
+
+            let frames = record(&strobe, &periods, 100, 22);
+            let lit: Vec<usize> = (0..frames.len())
+                .filter(|&idx| frames[idx] == RED)
+                .collect();
+            assert_eq!(lit, [0, 1, 20, 21]);
+            assert!(frames.iter().all(|&frame| frame == RED || frame == BLACK));
+        }
+
+        #[test]
+        fn fade_in_ramps_up_then_holds() {
+            let periods = AnimationPeriods::default();
+            let fade = Animation::new(Effect::FadeIn, RED, 200);
+
+            assert_eq!(
+                record(&fade, &periods, 375, 7),
+                [red(0), red(64), red(128), red(191), RED, RED, RED]
+            );
+            assert!(!fade.is_settled(200 + 1499, &periods));
+            assert!(fade.is_settled(200 + 1500, &periods));
+        }
+
+        #[test]
+        fn shift_swings_to_the_target_and_back() {
+            let periods = AnimationPeriods::default();
+            let shift = Animation::new(Effect::Shift, RED, 0).with_target(BLUE);
+
+            let frames = record(&shift, &periods, 1000, 5);
+            assert_eq!(frames[0], RED);
+            assert_eq!(frames[2], BLUE);
+            assert_eq!(frames[4], RED);
+            assert_eq!(frames[1], frames[3]);
+            assert_eq!(frames[1], mix_oklab(&RED, &BLUE, 0.5));
+            assert!(!shift.is_settled(0, &periods));
+            assert!(Animation::new(Effect::Shift, RED, 0).is_settled(0, &periods));
+        }
+
+        #[test]
+        fn periods_are_clamped() {
+            let periods = AnimationPeriods {
+                breathe: 0,
+                blink: 10,
+                strobe: 5000,
+                fade: 1,
+                crossfade: 0,
+            }
+            .clamped();
+
+            assert_eq!(periods.breathe, MIN_PERIOD);
+            assert_eq!(periods.blink, MIN_PERIOD);
+            assert_eq!(periods.strobe, 5000);
+            assert_eq!(periods.fade, MIN_PERIOD);
+            assert_eq!(periods.crossfade, 0);
+        }
+
+        #[test]
+        fn crossfade_eases_in_and_out() {
+            let crossfade = Crossfade::new(RED, 0.0, 1000, 1000);
+
+            let levels: Vec<f32> = (0..=4)
+                .map(|idx| crossfade.level(1000 + idx * 250, 1.0))
+                .collect();
+            assert_eq!(levels, [0.0, 0.15625, 0.5, 0.84375, 1.0]);
+
+            // Slow at both ends, fastest through the middle
+            let step = |now: u64| crossfade.level(now + 50, 1.0) - crossfade.level(now, 1.0);
+            assert!(step(1000) < step(1475));
+            assert!(step(1950) < step(1475));
+        }
+
+        #[test]
+        fn crossfade_runs_from_one_color_to_the_other() {
+            let crossfade = Crossfade::new(RED, 1.0, 0, 1000);
+
+            assert_eq!(crossfade.apply(0, &BLUE), RED);
+            assert_eq!(crossfade.apply(1000, &BLUE), BLUE);
+            assert_eq!(crossfade.apply(5000, &BLUE), BLUE);
+            assert_eq!(crossfade.level(1000, 0.25), 0.25);
+            assert!(!crossfade.is_done(999));
+            assert!(crossfade.is_done(1000));
+        }
+
+        #[test]
+        fn zero_length_crossfade_snaps() {
+            let crossfade = Crossfade::new(RED, 0.0, 100, 0);
+
+            assert!(crossfade.is_done(100));
+            assert_eq!(crossfade.apply(100, &BLUE), BLUE);
+            assert_eq!(crossfade.level(100, 1.0), 1.0);
+        }
+    }
+}
@@ -0,0 +1,361 @@
+pub mod color {
+    use rgb::RGB8;
+
+    // LED drive levels are linear in light output, but we see brightness on a
+    // roughly power-law curve. Anything steeper crushes the low end of an 8-bit LED.
+    pub const LED_GAMMA: f32 = 2.2;
+
+    // Hue in degrees (0-360), saturation and value from 0 to 1
+    #[derive(Debug, Copy, Clone, PartialEq)]
+    pub struct Hsv {
+        pub h: f32,
+        pub s: f32,
+        pub v: f32,
+    }
+
+    // Perceptual lightness L and the a/b opponent axes, see
+    // https://bottosson.github.io/posts/oklab/
+    #[derive(Debug, Copy, Clone, PartialEq)]
+    pub struct Oklab {
+        pub l: f32,
+        pub a: f32,
+        pub b: f32,
+    }
+
+    fn to_unit(channel: u8) -> f32 {
+        channel as f32 / 255.0
+    }
+
+    fn from_unit(channel: f32) -> u8 {
+        (channel.clamp(0.0, 1.0) * 255.0).round() as u8
+    }
+
+    pub fn srgb_to_linear(channel: f32) -> f32 {
+        if channel <= 0.04045 {
+            channel / 12.92
+        } else {
+            ((channel + 0.055) / 1.055).powf(2.4)
+        }
+    }
+
+    pub fn linear_to_srgb(channel: f32) -> f32 {
+        if channel <= 0.0031308 {
+            channel * 12.92
+        } else {
+            1.055 * channel.powf(1.0 / 2.4) - 0.055
+        }
+    }
+
+    pub fn rgb_to_hsv(color: &RGB8) -> Hsv {
+        let (r, g, b) = (to_unit(color.r), to_unit(color.g), to_unit(color.b));
+        let max = r.max(g).max(b);
+        let min = r.min(g).min(b);
+        let delta = max - min;
+
+        let h = if delta == 0.0 {
+            0.0
+        } else if max == r {
+            60.0 * ((g - b) / delta).rem_euclid(6.0)
+        } else if max == g {
+            60.0 * ((b - r) / delta + 2.0)
+        } else {
+            60.0 * ((r - g) / delta + 4.0)
+        };
+        let s = if max == 0.0 { 0.0 } else { delta / max };
+
+        Hsv { h, s, v: max }
+    }
+
+    pub fn hsv_to_rgb(hsv: &Hsv) -> RGB8 {
+        let h = hsv.h.rem_euclid(360.0);
+        let c = hsv.v * hsv.s;
+        let x = c * (1.0 - ((h / 60.0) % 2.0 - 1.0).abs());
+        let m = hsv.v - c;
+
+        let (r, g, b) = match h as u32 / 60 {
+            0 => (c, x, 0.0),
+            1 => (x, c, 0.0),
+            2 => (0.0, c, x),
+            3 => (0.0, x, c),
+            4 => (x, 0.0, c),
+            _ => (c, 0.0, x),
+        };
+
+        RGB8::new(from_unit(r + m), from_unit(g + m), from_unit(b + m))
+    }
+
+    // Constants are straight from the reference implementation
+    #[allow(clippy::excessive_precision)]
+    pub fn rgb_to_oklab(color: &RGB8) -> Oklab {
+        let r = srgb_to_linear(to_unit(color.r));
+        let g = srgb_to_linear(to_unit(color.g));
+        let b = srgb_to_linear(to_unit(color.b));
+
+        let l = (0.4122214708 * r + 0.5363325363 * g + 0.0514459929 * b).cbrt();
+        let m = (0.2119034982 * r + 0.6806995451 * g + 0.1073969566 * b).cbrt();
+        let s = (0.0883024619 * r + 0.2817188376 * g + 0.6299787005 * b).cbrt();
+
+        Oklab {
+            l: 0.2104542553 * l + 0.7936177850 * m - 0.0040720468 * s,
+            a: 1.9779984951 * l - 2.4285922050 * m + 0.4505937099 * s,
+            b: 0.0259040371 * l + 0.7827717662 * m - 0.8086757660 * s,
+        }
+    }
+
+    #[allow(clippy::excessive_precision)]
+    pub fn oklab_to_rgb(lab: &Oklab) -> RGB8 {
+        let l = (lab.l + 0.3963377774 * lab.a + 0.2158037573 * lab.b).powi(3);
+        let m = (lab.l - 0.1055613458 * lab.a - 0.0638541728 * lab.b).powi(3);
+        let s = (lab.l - 0.0894841775 * lab.a - 1.2914855480 * lab.b).powi(3);
+
+        let r = 4.0767416621 * l - 3.3077115913 * m + 0.2309699292 * s;
+        let g = -1.2684380046 * l + 2.6097574011 * m - 0.3413193965 * s;
+        let b = -0.0041960863 * l - 0.7034186147 * m + 1.7076147010 * s;
+
+        RGB8::new(
+            from_unit(linear_to_srgb(r)),
+            from_unit(linear_to_srgb(g)),
+            from_unit(linear_to_srgb(b)),
+        )
+    }
+
+    // `amount` of the way from `from` to `to`, through OKLab so the midpoints keep
+    // their lightness and saturation
+    pub fn mix_oklab(from: &RGB8, to: &RGB8, amount: f32) -> RGB8 {
+        let amount = amount.clamp(0.0, 1.0);
+        let from = rgb_to_oklab(from);
+        let to = rgb_to_oklab(to);
+
+        oklab_to_rgb(&Oklab {
+            l: from.l + (to.l - from.l) * amount,
+            a: from.a + (to.a - from.a) * amount,
+            b: from.b + (to.b - from.b) * amount,
+        })
+    }
+
+    // Dim a color by a perceptual `brightness` from 0 to 1 and convert it to the
+    // linear drive levels the LED wants
+    pub fn to_led(color: &RGB8, brightness: f32) -> RGB8 {
+        let brightness = brightness.clamp(0.0, 1.0);
+        let channel = |c: u8| from_unit((to_unit(c) * brightness).powf(LED_GAMMA));
+
+        RGB8::new(channel(color.r), channel(color.g), channel(color.b))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const RED: RGB8 = RGB8 { r: 255, g: 0, b: 0 };
+        const BLUE: RGB8 = RGB8 { r: 0, g: 0, b: 255 };
+        const WHITE: RGB8 = RGB8 {
+            r: 255,
+            g: 255,
+            b: 255,
+        };
+        const BLACK: RGB8 = RGB8 { r: 0, g: 0, b: 0 };
+
+        fn close(a: f32, b: f32, tolerance: f32) -> bool {
+            (a - b).abs() <= tolerance
+        }
+
+        // A spread of colors, including the greys and primaries
+        fn samples() -> impl Iterator<Item = RGB8> {
+            (0..=255u8)
+                .step_by(17)
+                .flat_map(|r| {
+                    [
+                        RGB8::new(r, 0, 0),
+                        RGB8::new(r, r, r),
+                        RGB8::new(r, 255 - r, 64),
+                    ]
+                })
+                .chain([RGB8::new(0, 255, 0), BLUE, RGB8::new(255, 128, 0)])
+        }
+
+        #[test]
+        fn srgb_to_linear_known_values() {
+            assert_eq!(srgb_to_linear(0.0), 0.0);
+            assert!(close(srgb_to_linear(1.0), 1.0, 1e-6));
+            // Mid grey is about a fifth of the light
+            assert!(close(srgb_to_linear(0.5), 0.214_041, 1e-5));
+            // Either side of the switch to the linear segment
+            assert!(close(srgb_to_linear(0.04), 0.04 / 12.92, 1e-7));
+            assert!(close(srgb_to_linear(0.05), 0.003_935, 1e-5));
+        }
+
+        #[test]
+        fn linear_to_srgb_known_values() {
+            assert_eq!(linear_to_srgb(0.0), 0.0);
+            assert!(close(linear_to_srgb(1.0), 1.0, 1e-6));
+            assert!(close(linear_to_srgb(0.214_041), 0.5, 1e-5));
+            assert!(close(linear_to_srgb(0.003), 0.003 * 12.92, 1e-7));
+        }
+
+        #[test]
+        fn srgb_linear_round_trip() {
+            for channel in 0..=255u8 {
+                let srgb = to_unit(channel);
+                assert!(close(linear_to_srgb(srgb_to_linear(srgb)), srgb, 1e-5));
+            }
+        }
+
+        #[test]
+        fn hsv_known_values() {
+            let hsv = |r, g, b| rgb_to_hsv(&RGB8::new(r, g, b));
+
+            assert_eq!(
+                hsv(255, 0, 0),
+                Hsv {
+                    h: 0.0,
+                    s: 1.0,
+                    v: 1.0
+                }
+            );
+            assert_eq!(
+                hsv(0, 255, 0),
+                Hsv {
+                    h: 120.0,
+                    s: 1.0,
+                    v: 1.0
+                }
+            );
+            assert_eq!(
+                hsv(0, 0, 255),
+                Hsv {
+                    h: 240.0,
+                    s: 1.0,
+                    v: 1.0
+                }
+            );
+            assert_eq!(
+                hsv(255, 0, 255),
+                Hsv {
+                    h: 300.0,
+                    s: 1.0,
+                    v: 1.0
+                }
+            );
+            assert_eq!(
+                hsv(255, 255, 255),
+                Hsv {
+                    h: 0.0,
+                    s: 0.0,
+                    v: 1.0
+                }
+            );
+            assert_eq!(
+                hsv(0, 0, 0),
+                Hsv {
+                    h: 0.0,
+                    s: 0.0,
+                    v: 0.0
+                }
+            );
+
+            let orange = hsv(255, 128, 0);
+            assert!(close(orange.h, 30.1, 0.1));
+            assert_eq!(orange.s, 1.0);
+
+            let yellow = Hsv {
+                h: 60.0,
+                s: 1.0,
+                v: 1.0,
+            };
+            assert_eq!(hsv_to_rgb(&yellow), RGB8::new(255, 255, 0));
+            let dark_cyan = Hsv {
+                h: 180.0,
+                s: 1.0,
+                v: 0.5,
+            };
+            assert_eq!(hsv_to_rgb(&dark_cyan), RGB8::new(0, 128, 128));
+            // Hue wraps around
+            let red = Hsv {
+                h: 360.0,
+                s: 1.0,
+                v: 1.0,
+            };
+            assert_eq!(hsv_to_rgb(&red), RED);
+            let red = Hsv {
+                h: -360.0,
+                s: 1.0,
+                v: 1.0,
+            };
+            assert_eq!(hsv_to_rgb(&red), RED);
+        }
+
+        #[test]
+        fn hsv_round_trip() {
+            for color in samples() {
+                assert_eq!(hsv_to_rgb(&rgb_to_hsv(&color)), color);
+            }
+        }
+
+        #[test]
+        fn oklab_known_values() {
+            let white = rgb_to_oklab(&WHITE);
+            assert!(close(white.l, 1.0, 1e-4));
+            assert!(close(white.a, 0.0, 1e-4));
+            assert!(close(white.b, 0.0, 1e-4));
+
+            let black = rgb_to_oklab(&BLACK);
+            assert_eq!(
+                black,
+                Oklab {
+                    l: 0.0,
+                    a: 0.0,
+                    b: 0.0
+                }
+            );
+
+            // Reference values from the OKLab post
+            let red = rgb_to_oklab(&RED);
+            assert!(close(red.l, 0.627_955, 1e-3));
+            assert!(close(red.a, 0.224_863, 1e-3));
+            assert!(close(red.b, 0.125_846, 1e-3));
+
+            let blue = rgb_to_oklab(&BLUE);
+            assert!(close(blue.l, 0.452_014, 1e-3));
+            assert!(close(blue.a, -0.032_457, 1e-3));
+            assert!(close(blue.b, -0.311_528, 1e-3));
+
+            // Greys have no color
+            let grey = rgb_to_oklab(&RGB8::new(128, 128, 128));
+            assert!(close(grey.a, 0.0, 1e-4));
+            assert!(close(grey.b, 0.0, 1e-4));
+        }
+
+        #[test]
+        fn oklab_round_trip() {
+            for color in samples() {
+                assert_eq!(oklab_to_rgb(&rgb_to_oklab(&color)), color);
+            }
+        }
+
+        #[test]
+        fn oklab_mix_ends_on_the_inputs() {
+            assert_eq!(mix_oklab(&RED, &BLUE, 0.0), RED);
+            assert_eq!(mix_oklab(&RED, &BLUE, 1.0), BLUE);
+            assert_eq!(mix_oklab(&RED, &BLUE, 2.0), BLUE);
+            assert_eq!(mix_oklab(&WHITE, &WHITE, 0.5), WHITE);
+        }
+
+        #[test]
+        fn oklab_mix_keeps_midpoints_bright() {
+            // Raw sRGB would give a dim (128, 0, 128) half way from red to blue
+            let mid = rgb_to_oklab(&mix_oklab(&RED, &BLUE, 0.5));
+            let expected = (rgb_to_oklab(&RED).l + rgb_to_oklab(&BLUE).l) / 2.0;
+            assert!(close(mid.l, expected, 0.01));
+            assert!(mid.l > rgb_to_oklab(&RGB8::new(128, 0, 128)).l);
+        }
+
+        #[test]
+        fn led_levels_follow_the_gamma() {
+            assert_eq!(to_led(&WHITE, 1.0), WHITE);
+            assert_eq!(to_led(&WHITE, 0.0), BLACK);
+            // Half brightness is well under half the drive
+            assert_eq!(to_led(&WHITE, 0.5).r, 55);
+            assert_eq!(to_led(&RED, 0.5), RGB8::new(55, 0, 0));
+        }
+    }
+}
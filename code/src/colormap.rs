@@ -0,0 +1,367 @@
+pub mod colormap {
+    use crate::glucose::glucose::{GlucoseLevel, GlucoseUnit};
+    use crate::lamp::lamp::{get_color_in_sweep, LedState, BLUE, GREEN, PURPLE, RED, WHITE};
+    use crate::server::server::{ServableData, ServableDataReq, ServableDataRsp, ServerData};
+    use crate::storage::storage::Storable;
+    use log::info;
+    use rgb_led::RGB8;
+    use serde::{Deserialize, Serialize};
+    use std::fmt;
+    use std::sync::mpsc;
+
+    // Keeps the stored map well under the server's request size limit
+    pub const MAX_STOPS: usize = 16;
+
+    #[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq)]
+    #[serde(rename_all = "lowercase")]
+    pub enum ColorMode {
+        // Sweep towards the next stop's color
+        Steady,
+        // Hold this stop's color and breathe
+        Breathe,
+    }
+
+    // Everything from `from` mg/dL up to the next stop
+    #[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq)]
+    pub struct ColorStop {
+        pub from: isize,
+        pub color: [u8; 3],
+        pub mode: ColorMode,
+    }
+
+    impl ColorStop {
+        const fn new(from: isize, color: RGB8, mode: ColorMode) -> Self {
+            ColorStop {
+                from,
+                color: [color.r, color.g, color.b],
+                mode,
+            }
+        }
+
+        fn rgb(&self) -> RGB8 {
+            RGB8::new(self.color[0], self.color[1], self.color[2])
+        }
+    }
+
+    // A stop as entered through the API, where the level can be in either unit
+    #[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq)]
+    pub struct ColorStopUpdate {
+        pub from: GlucoseLevel,
+        pub color: [u8; 3],
+        pub mode: ColorMode,
+    }
+
+    impl ColorStopUpdate {
+        pub fn resolve(&self, display_unit: GlucoseUnit) -> ColorStop {
+            ColorStop {
+                from: self.from.to_mgdl(display_unit),
+                color: self.color,
+                mode: self.mode,
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum ColorMapError {
+        Empty,
+        TooManyStops(usize),
+        // The first stop has to cover everything down to 0 mg/dL
+        GapAtStart(isize),
+        // Stops have to be in strictly increasing order
+        OutOfOrder(usize),
+    }
+
+    impl fmt::Display for ColorMapError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                Self::Empty => write!(f, "color map has no stops"),
+                Self::TooManyStops(count) => {
+                    write!(f, "{} stops is more than the {} allowed", count, MAX_STOPS)
+                }
+                Self::GapAtStart(from) => {
+                    write!(f, "first stop starts at {} mg/dL instead of 0", from)
+                }
+                Self::OutOfOrder(idx) => {
+                    write!(f, "stop {} doesn't start above the one before it", idx)
+                }
+            }
+        }
+    }
+
+    impl std::error::Error for ColorMapError {}
+
+    pub fn validate(stops: &[ColorStop]) -> Result<(), ColorMapError> {
+        let first = stops.first().ok_or(ColorMapError::Empty)?;
+
+        if stops.len() > MAX_STOPS {
+            return Err(ColorMapError::TooManyStops(stops.len()));
+        }
+
+        if first.from != 0 {
+            return Err(ColorMapError::GapAtStart(first.from));
+        }
+
+        match stops
+            .windows(2)
+            .position(|pair| pair[1].from <= pair[0].from)
+        {
+            Some(idx) => Err(ColorMapError::OutOfOrder(idx + 1)),
+            None => Ok(()),
+        }
+    }
+
+    // Red -> Green -> Blue -> Purple, breathing when out of range
+    const DEFAULT_STOPS: [ColorStop; 6] = [
+        ColorStop::new(0, RED, ColorMode::Breathe),
+        ColorStop::new(55, RED, ColorMode::Steady),
+        ColorStop::new(152, GREEN, ColorMode::Steady),
+        ColorStop::new(250, BLUE, ColorMode::Steady),
+        ColorStop::new(300, PURPLE, ColorMode::Breathe),
+        ColorStop::new(500, WHITE, ColorMode::Breathe),
+    ];
+
+    // Maps glucose values onto lamp colors
+    pub struct ColorMap {
+        stops: Vec<ColorStop>,
+        server_channel: Option<mpsc::Receiver<ServableDataReq>>,
+        save_data: bool,
+    }
+
+    impl Default for ColorMap {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl ColorMap {
+        pub fn new() -> Self {
+            ColorMap {
+                stops: DEFAULT_STOPS.to_vec(),
+                server_channel: None,
+                save_data: false,
+            }
+        }
+
+        pub fn stops(&self) -> &[ColorStop] {
+            &self.stops
+        }
+
+        pub fn set_stops(&mut self, stops: Vec<ColorStop>) -> Result<(), ColorMapError> {
+            validate(&stops)?;
+            self.stops = stops;
+            self.save_data = true;
+            Ok(())
+        }
+
+        // `value` in mg/dL
+        pub fn led_state(&self, value: isize) -> LedState {
+            let idx = self
+                .stops
+                .iter()
+                .rposition(|stop| stop.from <= value)
+                .unwrap_or(0);
+            let stop = &self.stops[idx];
+
+            match (stop.mode, self.stops.get(idx + 1)) {
+                (ColorMode::Steady, Some(next)) => LedState::Steady(get_color_in_sweep(
+                    &stop.rgb(),
+                    &next.rgb(),
+                    (next.from - stop.from) as usize,
+                    (value - stop.from).max(0),
+                )),
+                (ColorMode::Steady, None) => LedState::Steady(stop.rgb()),
+                (ColorMode::Breathe, _) => LedState::Breathe(stop.rgb()),
+            }
+        }
+
+        pub fn need_to_save(&self) -> bool {
+            self.save_data
+        }
+
+        pub fn saved(&mut self) {
+            self.save_data = false;
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct NvsColorMapState {
+        stops: Vec<ColorStop>,
+    }
+
+    impl Storable for ColorMap {
+        fn store_tag(&self) -> &str {
+            "color_map"
+        }
+
+        fn store_data(&self) -> Vec<u8> {
+            let data = NvsColorMapState {
+                stops: self.stops.clone(),
+            };
+
+            serde_json::to_string(&data).unwrap().into_bytes()
+        }
+
+        fn recall_data(&mut self, data: &[u8]) {
+            let nvs_state = serde_json::from_slice::<NvsColorMapState>(data).unwrap();
+            match validate(&nvs_state.stops) {
+                Ok(_) => self.stops = nvs_state.stops,
+                Err(error) => info!("Ignoring stored color map: {}", error),
+            }
+            self.save_data = false;
+        }
+    }
+
+    impl ServableData for ColorMap {
+        fn get_channel(&mut self) -> mpsc::Sender<ServableDataReq> {
+            let (tx, rx) = mpsc::channel::<ServableDataReq>();
+            self.server_channel = Some(rx);
+            tx
+        }
+
+        fn handle_server_req(&mut self) {
+            if let Some(channel) = &self.server_channel {
+                if let Ok(req) = channel.try_recv() {
+                    info!("color map got a request from server");
+
+                    if let ServableDataReq::Get(back_channel) = &req {
+                        info!("Sending color map to server");
+                        let mut rsp = ServerData::new();
+                        rsp.color_map = Some(self.stops.clone());
+                        back_channel.send(ServableDataRsp::Data(rsp)).unwrap();
+                    }
+
+                    if let ServableDataReq::Set(update) = &req {
+                        // The server has already validated these, but don't trust it
+                        if let Some(stops) = &update.color_map {
+                            if let Err(error) = self.set_stops(stops.clone()) {
+                                info!("Rejecting color map: {}", error);
+                            }
+                        }
+                    }
+
+                    if let ServableDataReq::Reset = &req {
+                        self.stops = DEFAULT_STOPS.to_vec();
+                        self.save_data = true;
+                    }
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn stop(from: isize, color: RGB8, mode: ColorMode) -> ColorStop {
+            ColorStop::new(from, color, mode)
+        }
+
+        // The ranges the lamp used before the map could be changed
+        fn old_from_glucose(value: isize) -> LedState {
+            match value {
+                0..55 => LedState::Breathe(RED),
+                55..152 => LedState::Steady(get_color_in_sweep(&RED, &GREEN, 152 - 55, value - 55)),
+                152..250 => {
+                    LedState::Steady(get_color_in_sweep(&GREEN, &BLUE, 250 - 152, value - 152))
+                }
+                250..300 => {
+                    LedState::Steady(get_color_in_sweep(&BLUE, &PURPLE, 300 - 250, value - 250))
+                }
+                300..500 => LedState::Breathe(PURPLE),
+                _ => LedState::Breathe(WHITE),
+            }
+        }
+
+        #[test]
+        fn default_map_matches_the_old_ranges() {
+            let map = ColorMap::new();
+            assert_eq!(validate(map.stops()), Ok(()));
+
+            for value in 0..=600 {
+                assert_eq!(
+                    map.led_state(value),
+                    old_from_glucose(value),
+                    "{} mg/dL",
+                    value
+                );
+            }
+        }
+
+        #[test]
+        fn sweeps_from_each_stop_towards_the_next() {
+            let mut map = ColorMap::new();
+            map.set_stops(vec![
+                stop(0, RED, ColorMode::Steady),
+                stop(100, GREEN, ColorMode::Breathe),
+                stop(200, BLUE, ColorMode::Steady),
+            ])
+            .unwrap();
+
+            assert_eq!(map.led_state(0), LedState::Steady(RED));
+            assert_eq!(
+                map.led_state(50),
+                LedState::Steady(get_color_in_sweep(&RED, &GREEN, 100, 50))
+            );
+            assert_eq!(
+                map.led_state(99),
+                LedState::Steady(get_color_in_sweep(&RED, &GREEN, 100, 99))
+            );
+            // A breathing stop holds its own color right up to the next stop
+            assert_eq!(map.led_state(100), LedState::Breathe(GREEN));
+            assert_eq!(map.led_state(199), LedState::Breathe(GREEN));
+            // The last stop has nothing to sweep towards
+            assert_eq!(map.led_state(200), LedState::Steady(BLUE));
+            assert_eq!(map.led_state(1000), LedState::Steady(BLUE));
+        }
+
+        #[test]
+        fn rejects_an_empty_map() {
+            assert_eq!(validate(&[]), Err(ColorMapError::Empty));
+        }
+
+        #[test]
+        fn rejects_too_many_stops() {
+            let stops: Vec<_> = (0..=MAX_STOPS as isize)
+                .map(|idx| stop(idx * 10, RED, ColorMode::Steady))
+                .collect();
+
+            assert_eq!(validate(&stops[..MAX_STOPS]), Ok(()));
+            assert_eq!(
+                validate(&stops),
+                Err(ColorMapError::TooManyStops(MAX_STOPS + 1))
+            );
+        }
+
+        #[test]
+        fn rejects_a_gap_at_the_start() {
+            let stops = [stop(40, RED, ColorMode::Steady)];
+            assert_eq!(validate(&stops), Err(ColorMapError::GapAtStart(40)));
+        }
+
+        #[test]
+        fn rejects_stops_out_of_order() {
+            let stops = [
+                stop(0, RED, ColorMode::Steady),
+                stop(100, GREEN, ColorMode::Steady),
+                stop(100, BLUE, ColorMode::Steady),
+            ];
+            assert_eq!(validate(&stops), Err(ColorMapError::OutOfOrder(2)));
+
+            let stops = [
+                stop(0, RED, ColorMode::Steady),
+                stop(150, GREEN, ColorMode::Steady),
+                stop(100, BLUE, ColorMode::Steady),
+            ];
+            assert_eq!(validate(&stops), Err(ColorMapError::OutOfOrder(2)));
+        }
+
+        #[test]
+        fn a_rejected_map_keeps_the_old_one() {
+            let mut map = ColorMap::new();
+            assert!(map.set_stops(vec![]).is_err());
+
+            assert_eq!(map.stops(), DEFAULT_STOPS);
+            assert!(!map.need_to_save());
+        }
+    }
+}
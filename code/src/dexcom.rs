@@ -1,23 +1,182 @@
 pub mod dexcom {
+    use crate::glucose::glucose::{GlucoseError, GlucoseReading, GlucoseSource};
+    use crate::http::http::{BodyError, HttpTransport};
     use crate::server::server::{ServableData, ServableDataReq, ServableDataRsp, ServerData};
+    use crate::share::share::{self, ShareReading};
     use crate::storage::storage::Storable;
-    use embedded_svc::{http::client::Client, io::Write, utils::io};
-    use esp_idf_svc::http::client::{Configuration as HttpConfiguration, EspHttpConnection};
-    use log::{error, info};
+    use crate::sys::sys::uptime;
+    use log::info;
+    use serde::de::DeserializeOwned;
     use serde::{Deserialize, Serialize};
     use serde_json;
+    use std::fmt;
     use std::sync::mpsc;
 
-    pub const APPLICATION_ID: &'static str = "d89443d2-327c-4a6f-89e5-496bbb0317db";
+    pub const APPLICATION_ID: &str = "d89443d2-327c-4a6f-89e5-496bbb0317db";
+    pub const JP_APPLICATION_ID: &str = "d8665ade-9673-4e27-9ff6-92db4ce13d13";
 
-    pub const BASE_URL: &'static str = "https://share1.dexcom.com/ShareWebServices/Services";
+    pub const US_BASE_URL: &str = "https://share1.dexcom.com/ShareWebServices/Services";
+    pub const OUS_BASE_URL: &str = "https://shareous1.dexcom.com/ShareWebServices/Services";
+    pub const JP_BASE_URL: &str = "https://share.dexcom.jp/ShareWebServices/Services";
 
-    pub const LOGIN_ID_ENDPOINT: &'static str = "General/LoginPublisherAccountById";
-    pub const AUTHENTICATE_ENDPOINT: &'static str = "General/AuthenticatePublisherAccount";
-    pub const GLUCOSE_READINGS_ENDPOINT: &'static str =
-        "Publisher/ReadPublisherLatestGlucoseValues";
+    pub const LOGIN_ID_ENDPOINT: &str = "General/LoginPublisherAccountById";
+    pub const AUTHENTICATE_ENDPOINT: &str = "General/AuthenticatePublisherAccount";
+    pub const GLUCOSE_READINGS_ENDPOINT: &str = "Publisher/ReadPublisherLatestGlucoseValues";
 
     pub const MAX_MAX_COUNT: isize = 288;
+    pub const MAX_MINUTES: isize = 1440;
+
+    // Share hands this account ID back instead of an error when the login fails
+    const NULL_ACCOUNT_ID: &str = "00000000-0000-0000-0000-000000000000";
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum DexcomError {
+        NoCredentials,
+        InvalidCredentials,
+        AccountNotFound,
+        SessionExpired,
+        SessionInvalid,
+        RateLimited,
+        NoReadings,
+        HttpStatus(u16),
+        TruncatedBody(String),
+        MalformedBody(String),
+        Transport(String),
+    }
+
+    impl DexcomError {
+        // Classify a failed Share request from its HTTP status and error body
+        fn from_response(status: u16, body: &str) -> Self {
+            if let Ok(share_error) = serde_json::from_str::<DexcomShareError>(body) {
+                info!(
+                    "Share error {}: {}",
+                    share_error.code,
+                    share_error.message.as_deref().unwrap_or("")
+                );
+
+                match share_error.code.as_str() {
+                    "AccountPasswordInvalid" | "SSO_AuthenticatePasswordInvalid" => {
+                        return Self::InvalidCredentials
+                    }
+                    "SSO_AuthenticateAccountNotFound" | "AccountNotFound" => {
+                        return Self::AccountNotFound
+                    }
+                    "SessionIdNotFound" => return Self::SessionExpired,
+                    "SessionNotValid" => return Self::SessionInvalid,
+                    "SSO_AuthenticateMaxAttemptsExceeded" => return Self::RateLimited,
+                    "InvalidArgument" | "SSO_InternalError" => {
+                        let message = share_error.message.unwrap_or_default();
+                        if message.contains("accountName")
+                            || message.contains("password")
+                            || message.contains("Cannot Authenticate")
+                        {
+                            return Self::InvalidCredentials;
+                        }
+                    }
+                    _ => (),
+                }
+            }
+
+            match status {
+                429 => Self::RateLimited,
+                _ => Self::HttpStatus(status),
+            }
+        }
+
+        // Errors that new credentials are needed to get past
+        pub fn is_auth_failure(&self) -> bool {
+            matches!(
+                self,
+                Self::NoCredentials | Self::InvalidCredentials | Self::AccountNotFound
+            )
+        }
+
+        // Errors that a new session will fix
+        pub fn is_session_failure(&self) -> bool {
+            matches!(self, Self::SessionExpired | Self::SessionInvalid)
+        }
+    }
+
+    impl fmt::Display for DexcomError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                Self::NoCredentials => write!(f, "no dexcom credentials stored"),
+                Self::InvalidCredentials => write!(f, "invalid dexcom credentials"),
+                Self::AccountNotFound => write!(f, "dexcom account not found"),
+                Self::SessionExpired => write!(f, "dexcom session expired"),
+                Self::SessionInvalid => write!(f, "dexcom session not valid"),
+                Self::RateLimited => write!(f, "too many requests to dexcom"),
+                Self::NoReadings => write!(f, "no glucose readings available"),
+                Self::HttpStatus(status) => write!(f, "unexpected http status {}", status),
+                Self::TruncatedBody(e) => write!(f, "incomplete response: {}", e),
+                Self::MalformedBody(e) => write!(f, "malformed response body: {}", e),
+                Self::Transport(e) => write!(f, "transport failure: {}", e),
+            }
+        }
+    }
+
+    impl std::error::Error for DexcomError {}
+
+    impl From<BodyError> for DexcomError {
+        fn from(error: BodyError) -> Self {
+            match error {
+                BodyError::Truncated(truncated) => Self::TruncatedBody(truncated.to_string()),
+                BodyError::Malformed(e) => Self::MalformedBody(e),
+            }
+        }
+    }
+
+    impl From<DexcomError> for GlucoseError {
+        fn from(error: DexcomError) -> Self {
+            match error {
+                DexcomError::NoCredentials => Self::NoCredentials,
+                DexcomError::InvalidCredentials | DexcomError::AccountNotFound => {
+                    Self::AuthFailed(error.to_string())
+                }
+                DexcomError::SessionExpired | DexcomError::SessionInvalid => {
+                    Self::SessionLost(error.to_string())
+                }
+                DexcomError::RateLimited => Self::RateLimited,
+                DexcomError::NoReadings => Self::NoReadings,
+                _ => Self::Unavailable(error.to_string()),
+            }
+        }
+    }
+
+    #[derive(Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    struct DexcomShareError {
+        code: String,
+        message: Option<String>,
+    }
+
+    // Dexcom Share accounts live on a different server depending on where the
+    // account was created
+    #[derive(Deserialize, Serialize, Debug, Default, Copy, Clone, PartialEq)]
+    #[serde(rename_all = "lowercase")]
+    pub enum DexcomRegion {
+        #[default]
+        Us,
+        Ous,
+        Jp,
+    }
+
+    impl DexcomRegion {
+        pub fn base_url(&self) -> &'static str {
+            match self {
+                Self::Us => US_BASE_URL,
+                Self::Ous => OUS_BASE_URL,
+                Self::Jp => JP_BASE_URL,
+            }
+        }
+
+        pub fn application_id(&self) -> &'static str {
+            match self {
+                Self::Us | Self::Ous => APPLICATION_ID,
+                Self::Jp => JP_APPLICATION_ID,
+            }
+        }
+    }
 
     #[derive(Deserialize, Serialize)]
     #[serde(rename_all = "camelCase")]
@@ -43,252 +202,179 @@ pub mod dexcom {
         max_count: isize,
     }
 
-    #[derive(Deserialize, Serialize, Debug)]
-    #[serde(rename_all = "lowercase")]
-    struct DexcomGlucoseReading {
-        pub wt: String,
-        pub st: String,
-        pub dt: String,
-        pub value: isize,
-        pub trend: String,
-    }
-
-    #[derive(Debug, Copy, Clone)]
-    pub enum GlucoseTrend {
-        NoTrend,
-        DoubleUp,
-        SingleUp,
-        FortyFiveUp,
-        Flat,
-        FortyFiveDown,
-        SingleDown,
-        DoubleDown,
-        NotComputable,
-        RateOutOfRange,
-    }
-
-    impl GlucoseTrend {
-        pub fn from_str(trend: &str) -> Self {
-            match trend {
-                "DoubleUp" => Self::DoubleUp,
-                "SingleUp" => Self::SingleUp,
-                "FortyFiveUp" => Self::FortyFiveUp,
-                "Flat" => Self::Flat,
-                "FortyFiveDown" => Self::FortyFiveDown,
-                "SingleDown" => Self::SingleDown,
-                "DoubleDown" => Self::DoubleDown,
-                "NotComputable" => Self::NotComputable,
-                "RateOutOfRange" => Self::RateOutOfRange,
-                _ => Self::NoTrend,
-            }
-        }
-    }
-
-    #[derive(Debug, Copy, Clone)]
-    pub struct GlucoseReading {
-        pub time: i64,
-        pub value: isize,
-        pub trend: GlucoseTrend,
-    }
-
-    impl GlucoseReading {
-        pub fn new() -> Self {
-            Self {
-                time: 0,
-                value: 0,
-                trend: GlucoseTrend::NoTrend,
-            }
-        }
-    }
-
-    pub struct Dexcom {
-        client: Client<EspHttpConnection>,
+    pub struct Dexcom<T: HttpTransport> {
+        transport: T,
         user_id: String,
         session: String,
+        session_started: Option<u64>,
+        relogin_count: u32,
         user_name: Option<String>,
         user_pass: Option<String>,
+        region: DexcomRegion,
         server_channel: Option<mpsc::Receiver<ServableDataReq>>,
         save_data: bool,
     }
 
-    impl Dexcom {
-        pub fn new() -> Self {
-            let connection = EspHttpConnection::new(&HttpConfiguration {
-                use_global_ca_store: true,
-                crt_bundle_attach: Some(esp_idf_svc::sys::esp_crt_bundle_attach),
-                ..Default::default()
-            })
-            .unwrap();
-
-            let client = Client::wrap(connection);
-
+    impl<T: HttpTransport> Dexcom<T> {
+        pub fn new(transport: T) -> Self {
             Dexcom {
-                client,
+                transport,
                 user_id: "".to_string(),
                 session: "".to_string(),
+                session_started: None,
+                relogin_count: 0,
                 user_name: None,
                 user_pass: None,
+                region: DexcomRegion::default(),
                 server_channel: None,
                 save_data: false,
             }
         }
 
+        pub fn transport(&self) -> &T {
+            &self.transport
+        }
+
         pub fn has_creds(&self) -> bool {
-            if self.user_name == None {
-                return false;
+            self.user_name.is_some() && self.user_pass.is_some()
+        }
+
+        pub fn reset_creds(&mut self) {
+            self.user_name = None;
+            self.user_pass = None;
+            self.save_data = true;
+        }
+
+        pub fn connect(&mut self) -> Result<(), DexcomError> {
+            let (Some(uname), Some(upass)) = (self.user_name.clone(), self.user_pass.clone())
+            else {
+                return Err(DexcomError::NoCredentials);
+            };
+
+            self.user_id = self.get_user_id(&uname, &upass)?;
+            if self.user_id == NULL_ACCOUNT_ID {
+                return Err(DexcomError::InvalidCredentials);
             }
-            if self.user_pass == None {
-                return false;
+
+            self.session = self.get_session(&upass)?;
+            if self.session == NULL_ACCOUNT_ID {
+                return Err(DexcomError::InvalidCredentials);
             }
 
-            true
+            self.session_started = Some(uptime());
+
+            Ok(())
         }
 
-        pub fn connect(&mut self) -> anyhow::Result<()> {
-            let uname = self.user_name.clone().unwrap();
-            let upass = self.user_pass.clone().unwrap();
+        // Log in again with the stored credentials after the session went stale
+        fn renew_session(&mut self) -> Result<(), DexcomError> {
+            self.session_started = None;
+            self.relogin_count += 1;
+            self.connect()
+        }
 
-            self.user_id = self.get_user_id(&uname, &upass).unwrap();
-            self.session = self.get_session(&upass).unwrap();
+        // Seconds since the current session was created
+        pub fn session_age(&self) -> Option<u64> {
+            self.session_started
+                .map(|started| uptime().saturating_sub(started))
+        }
 
-            return Ok(());
+        // Number of times the session was renewed since boot
+        pub fn relogin_count(&self) -> u32 {
+            self.relogin_count
         }
 
-        fn get_user_id(&mut self, acct_name: &str, pass: &str) -> anyhow::Result<String> {
+        fn get_user_id(&mut self, acct_name: &str, pass: &str) -> Result<String, DexcomError> {
             let login_ctx = DexcomLogin {
                 account_name: acct_name.to_string(),
                 password: pass.to_string(),
-                application_id: APPLICATION_ID.into(),
+                application_id: self.region.application_id().into(),
             };
 
-            let auth_url = format!("{}/{}", BASE_URL, AUTHENTICATE_ENDPOINT);
+            let auth_url = format!("{}/{}", self.region.base_url(), AUTHENTICATE_ENDPOINT);
 
-            let user_id_json = Dexcom::post(
-                &mut self.client,
-                &auth_url,
-                &(serde_json::to_string(&login_ctx).unwrap()),
-            )
-            .unwrap();
-
-            Ok(serde_json::from_str(&user_id_json).unwrap())
+            self.post(&auth_url, &(serde_json::to_string(&login_ctx).unwrap()))
         }
 
-        fn get_session(&mut self, pass: &str) -> anyhow::Result<String> {
+        fn get_session(&mut self, pass: &str) -> Result<String, DexcomError> {
             let session_ctx = DexcomSession {
                 account_id: self.user_id.to_string(),
                 password: pass.to_string(),
-                application_id: APPLICATION_ID.into(),
+                application_id: self.region.application_id().into(),
             };
 
-            let login_url = format!("{}/{}", BASE_URL, LOGIN_ID_ENDPOINT);
+            let login_url = format!("{}/{}", self.region.base_url(), LOGIN_ID_ENDPOINT);
 
-            let session_json = Dexcom::post(
-                &mut self.client,
-                &login_url,
-                &(serde_json::to_string(&session_ctx).unwrap()),
-            )
-            .unwrap();
+            self.post(&login_url, &(serde_json::to_string(&session_ctx).unwrap()))
+        }
 
-            Ok(serde_json::from_str(&session_json).unwrap())
+        pub fn get_latest_glucose(&mut self) -> Result<GlucoseReading, DexcomError> {
+            self.get_glucose(5, 1)?
+                .first()
+                .copied()
+                .ok_or(DexcomError::NoReadings)
         }
 
-        pub fn get_latest_glucose(&mut self) -> anyhow::Result<GlucoseReading> {
-            match self.get_glucose(5, 1) {
-                Ok(vec) => {
-                    if vec.len() > 0 {
-                        return Ok(vec[0]);
-                    } else {
-                        return Err(anyhow::anyhow!("No measurement"));
-                    }
+        // Every reading from the last 24 hours, newest first
+        pub fn get_glucose_history(&mut self) -> Result<Vec<GlucoseReading>, DexcomError> {
+            self.get_glucose(MAX_MINUTES, MAX_MAX_COUNT)
+        }
+
+        pub fn get_glucose(
+            &mut self,
+            minutes: isize,
+            max_count: isize,
+        ) -> Result<Vec<GlucoseReading>, DexcomError> {
+            match self.fetch_glucose(minutes, max_count) {
+                Err(error) if error.is_session_failure() => {
+                    // Sessions expire after a few hours, get a new one and try once more
+                    info!("Dexcom session lost ({}), renewing", error);
+                    self.renew_session()?;
+                    self.fetch_glucose(minutes, max_count)
                 }
-                Err(error) => Err(error),
+                result => result,
             }
         }
 
-        pub fn get_glucose(
+        fn fetch_glucose(
             &mut self,
             minutes: isize,
             max_count: isize,
-        ) -> anyhow::Result<Vec<GlucoseReading>> {
+        ) -> Result<Vec<GlucoseReading>, DexcomError> {
             let glucose_ctx = DexcomGlucose {
                 session_id: self.session.to_string(),
                 minutes,
                 max_count,
             };
 
-            let glucose_url = format!("{}/{}", BASE_URL, GLUCOSE_READINGS_ENDPOINT);
+            let glucose_url = format!("{}/{}", self.region.base_url(), GLUCOSE_READINGS_ENDPOINT);
 
-            let mut glucose_json = Dexcom::post(
-                &mut self.client,
+            let readings: Vec<ShareReading> = self.post(
                 &glucose_url,
                 &(serde_json::to_string(&glucose_ctx).unwrap()),
-            )
-            .unwrap();
-
-            // Fix the json field names
-            glucose_json = glucose_json.replace("WT", "wt");
-            glucose_json = glucose_json.replace("ST", "st");
-            glucose_json = glucose_json.replace("DT", "dt");
-            glucose_json = glucose_json.replace("Value", "value");
-            glucose_json = glucose_json.replace("Trend", "trend");
-
-            let glucose_readings: Vec<DexcomGlucoseReading> =
-                serde_json::from_str(&glucose_json).unwrap();
-
-            Ok(glucose_readings
-                .into_iter()
-                .map(|reading| {
-                    let start_bytes = reading.wt.find("(").unwrap_or(0) + 1;
-                    let end_bytes = reading.wt.find(")").unwrap_or(reading.wt.len());
-                    let time: i64 = reading.wt[start_bytes..end_bytes]
-                        .to_string()
-                        .parse()
-                        .unwrap();
-                    GlucoseReading {
-                        time,
-                        value: reading.value,
-                        trend: GlucoseTrend::from_str(&reading.trend),
-                    }
-                })
-                .collect())
+            )?;
+
+            share::to_readings(readings).map_err(|e| DexcomError::MalformedBody(e.to_string()))
         }
 
-        fn post(
-            client: &mut Client<EspHttpConnection>,
+        fn post<D: DeserializeOwned>(
+            &mut self,
             url: &str,
             payload: &str,
-        ) -> anyhow::Result<String> {
-            let content_length_header = format!("{}", payload.len());
-            let headers = [
-                ("accept-encoding", "application/json"),
-                ("content-type", "application/json"),
-                ("content-length", &*content_length_header),
-            ];
-
-            let mut request = client.post(url, &headers)?;
-            request.write_all(payload.as_bytes())?;
-            request.flush()?;
-            info!("-> POST {}", url);
-            let mut response = request.submit()?;
-
-            let status = response.status();
-            info!("<- {}", status);
-            let mut buf = [0u8; 4096];
-            let bytes_read = io::try_read_full(&mut response, &mut buf).map_err(|e| e.0)?;
-            info!("Read {} bytes", bytes_read);
-            match std::str::from_utf8(&buf[0..bytes_read]) {
-                Ok(body_string) => {
-                    info!(
-                        "Response body (truncated to {} bytes): {:?}",
-                        buf.len(),
-                        body_string
-                    );
-                    return Ok(body_string.to_owned());
-                }
-                Err(e) => error!("Error decoding response body: {}", e),
-            };
+        ) -> Result<D, DexcomError> {
+            let response = self
+                .transport
+                .post(url, &[], payload)
+                .map_err(|e| DexcomError::Transport(e.to_string()))?;
+
+            if response.status != 200 {
+                let status = response.status;
+                let body = response.text()?;
+                return Err(DexcomError::from_response(status, &body));
+            }
 
-            Ok("".to_owned())
+            // Parse as the body comes in, a full day of readings is most of the heap
+            Ok(response.json()?)
         }
 
         pub fn need_to_save(&self) -> bool {
@@ -300,21 +386,46 @@ pub mod dexcom {
         }
     }
 
+    impl<T: HttpTransport> GlucoseSource for Dexcom<T> {
+        fn has_creds(&self) -> bool {
+            Dexcom::has_creds(self)
+        }
+
+        fn reset_creds(&mut self) {
+            Dexcom::reset_creds(self)
+        }
+
+        fn connect(&mut self) -> Result<(), GlucoseError> {
+            Ok(Dexcom::connect(self)?)
+        }
+
+        fn get_latest_glucose(&mut self) -> Result<GlucoseReading, GlucoseError> {
+            Ok(Dexcom::get_latest_glucose(self)?)
+        }
+
+        fn get_glucose_history(&mut self) -> Result<Vec<GlucoseReading>, GlucoseError> {
+            Ok(Dexcom::get_glucose_history(self)?)
+        }
+    }
+
     #[derive(Serialize, Deserialize)]
     struct NvsDexcomState {
         user_name: Option<String>,
         user_pass: Option<String>,
+        #[serde(default)]
+        region: DexcomRegion,
     }
 
-    impl Storable for Dexcom {
+    impl<T: HttpTransport> Storable for Dexcom<T> {
         fn store_tag(&self) -> &str {
-            return &"dexcom_creds";
+            "dexcom_creds"
         }
 
         fn store_data(&self) -> Vec<u8> {
             let data = NvsDexcomState {
                 user_name: self.user_name.to_owned(),
                 user_pass: self.user_pass.to_owned(),
+                region: self.region,
             };
 
             serde_json::to_string(&data).unwrap().into_bytes()
@@ -324,11 +435,12 @@ pub mod dexcom {
             let nvs_state = serde_json::from_slice::<NvsDexcomState>(data).unwrap();
             self.user_name = nvs_state.user_name;
             self.user_pass = nvs_state.user_pass;
+            self.region = nvs_state.region;
             self.save_data = false;
         }
     }
 
-    impl ServableData for Dexcom {
+    impl<T: HttpTransport> ServableData for Dexcom<T> {
         fn get_channel(&mut self) -> mpsc::Sender<ServableDataReq> {
             let (tx, rx) = mpsc::channel::<ServableDataReq>();
             self.server_channel = Some(rx);
@@ -345,6 +457,9 @@ pub mod dexcom {
                         let mut rsp = ServerData::new();
                         rsp.dexcom_user_stored = Some(self.user_name.is_some());
                         rsp.dexcom_pass_stored = Some(self.user_pass.is_some());
+                        rsp.dexcom_region = Some(self.region);
+                        rsp.dexcom_session_age = self.session_age();
+                        rsp.dexcom_relogins = Some(self.relogin_count());
                         back_channel.send(ServableDataRsp::Data(rsp)).unwrap();
                     }
 
@@ -358,15 +473,219 @@ pub mod dexcom {
                             self.user_pass = Some(dexcom_pass.clone());
                             self.save_data = true;
                         }
+
+                        if let Some(dexcom_region) = &update.dexcom_region {
+                            self.region = *dexcom_region;
+                            self.save_data = true;
+                        }
                     }
 
                     if let ServableDataReq::Reset = &req {
                         self.user_name = None;
                         self.user_pass = None;
+                        self.region = DexcomRegion::default();
                         self.save_data = true;
                     }
                 }
             }
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::glucose::glucose::GlucoseTrend;
+        use crate::http::http::ScriptedTransport;
+
+        const READINGS: &str = r#"[{"WT":"Date(1700000300000)","ST":"Date(1700000300000)","DT":"Date(1700000300000-0500)","Value":123,"Trend":"Flat"}]"#;
+        const SESSION_EXPIRED: &str =
+            r#"{"Code":"SessionIdNotFound","Message":"Session ID not found"}"#;
+
+        fn dexcom(transport: ScriptedTransport) -> Dexcom<ScriptedTransport> {
+            let mut dexcom = Dexcom::new(transport);
+            dexcom.user_name = Some("user".to_string());
+            dexcom.user_pass = Some("pass".to_string());
+            dexcom
+        }
+
+        // The account and session IDs Share hands back for a good login
+        fn login(transport: ScriptedTransport) -> ScriptedTransport {
+            transport
+                .respond(200, r#""1234-account""#)
+                .respond(200, r#""5678-session""#)
+        }
+
+        #[test]
+        fn login_then_session_then_glucose() {
+            let mut dexcom = dexcom(login(ScriptedTransport::new()).respond(200, READINGS));
+
+            dexcom.connect().unwrap();
+            let reading = dexcom.get_latest_glucose().unwrap();
+
+            assert_eq!(reading.time, 1700000300000);
+            assert_eq!(reading.value, 123);
+            assert!(matches!(reading.trend, GlucoseTrend::Flat));
+            assert_eq!(reading.display_offset, Some(-300));
+
+            let requests = dexcom.transport().requests();
+            assert_eq!(requests.len(), 3);
+            assert_eq!(
+                requests[0].0,
+                format!("{}/{}", US_BASE_URL, AUTHENTICATE_ENDPOINT)
+            );
+            assert!(requests[0].1.contains(r#""accountName":"user""#));
+            assert_eq!(
+                requests[1].0,
+                format!("{}/{}", US_BASE_URL, LOGIN_ID_ENDPOINT)
+            );
+            assert!(requests[1].1.contains(r#""accountId":"1234-account""#));
+            assert_eq!(
+                requests[2].0,
+                format!("{}/{}", US_BASE_URL, GLUCOSE_READINGS_ENDPOINT)
+            );
+            assert!(requests[2].1.contains(r#""sessionId":"5678-session""#));
+        }
+
+        #[test]
+        fn renews_an_expired_session_once() {
+            let transport = login(ScriptedTransport::new()).respond(500, SESSION_EXPIRED);
+            let mut dexcom = dexcom(login(transport).respond(200, READINGS));
+
+            dexcom.connect().unwrap();
+            let reading = dexcom.get_latest_glucose().unwrap();
+
+            assert_eq!(reading.value, 123);
+            assert_eq!(dexcom.relogin_count(), 1);
+            assert_eq!(dexcom.transport().remaining(), 0);
+            assert_eq!(dexcom.transport().requests().len(), 6);
+        }
+
+        #[test]
+        fn gives_up_when_the_renewed_session_fails_too() {
+            let transport = login(ScriptedTransport::new()).respond(500, SESSION_EXPIRED);
+            let mut dexcom = dexcom(login(transport).respond(500, SESSION_EXPIRED));
+
+            dexcom.connect().unwrap();
+
+            assert_eq!(
+                dexcom.get_latest_glucose().unwrap_err(),
+                DexcomError::SessionExpired
+            );
+            assert_eq!(dexcom.relogin_count(), 1);
+        }
+
+        #[test]
+        fn null_account_id_stops_the_login() {
+            let transport = ScriptedTransport::new()
+                .respond(200, &format!("{:?}", NULL_ACCOUNT_ID))
+                .respond(200, r#""5678-session""#);
+            let mut dexcom = dexcom(transport);
+
+            assert_eq!(
+                dexcom.connect().unwrap_err(),
+                DexcomError::InvalidCredentials
+            );
+            assert_eq!(dexcom.transport().requests().len(), 1);
+        }
+
+        #[test]
+        fn connect_needs_credentials() {
+            let mut dexcom = Dexcom::new(ScriptedTransport::new());
+
+            assert_eq!(dexcom.connect().unwrap_err(), DexcomError::NoCredentials);
+            assert!(dexcom.transport().requests().is_empty());
+        }
+
+        #[test]
+        fn transport_failures_are_reported() {
+            let mut dexcom = dexcom(ScriptedTransport::new().fail("connection reset"));
+
+            assert!(matches!(
+                dexcom.connect().unwrap_err(),
+                DexcomError::Transport(_)
+            ));
+        }
+
+        #[test]
+        fn cut_off_and_garbled_bodies_are_reported() {
+            let transport =
+                login(ScriptedTransport::new()).respond_partial(200, "[{", READINGS.len());
+            let mut cut_off = dexcom(transport);
+            cut_off.connect().unwrap();
+            assert!(matches!(
+                cut_off.get_latest_glucose().unwrap_err(),
+                DexcomError::TruncatedBody(_)
+            ));
+
+            let mut garbled = dexcom(ScriptedTransport::new().respond(200, "not json"));
+            assert!(matches!(
+                garbled.connect().unwrap_err(),
+                DexcomError::MalformedBody(_)
+            ));
+        }
+
+        #[test]
+        fn classifies_share_errors() {
+            let cases = [
+                (
+                    500,
+                    r#"{"Code":"AccountPasswordInvalid"}"#,
+                    DexcomError::InvalidCredentials,
+                ),
+                (
+                    500,
+                    r#"{"Code":"SSO_AuthenticatePasswordInvalid","Message":"x"}"#,
+                    DexcomError::InvalidCredentials,
+                ),
+                (
+                    500,
+                    r#"{"Code":"SSO_AuthenticateAccountNotFound"}"#,
+                    DexcomError::AccountNotFound,
+                ),
+                (
+                    500,
+                    r#"{"Code":"AccountNotFound"}"#,
+                    DexcomError::AccountNotFound,
+                ),
+                (500, SESSION_EXPIRED, DexcomError::SessionExpired),
+                (
+                    500,
+                    r#"{"Code":"SessionNotValid"}"#,
+                    DexcomError::SessionInvalid,
+                ),
+                (
+                    500,
+                    r#"{"Code":"SSO_AuthenticateMaxAttemptsExceeded"}"#,
+                    DexcomError::RateLimited,
+                ),
+                (
+                    500,
+                    r#"{"Code":"InvalidArgument","Message":"accountName must be set"}"#,
+                    DexcomError::InvalidCredentials,
+                ),
+                (
+                    500,
+                    r#"{"Code":"InvalidArgument","Message":"something else"}"#,
+                    DexcomError::HttpStatus(500),
+                ),
+                (
+                    500,
+                    r#"{"Code":"SomethingNew"}"#,
+                    DexcomError::HttpStatus(500),
+                ),
+                (429, "", DexcomError::RateLimited),
+                (503, "<html>down</html>", DexcomError::HttpStatus(503)),
+            ];
+
+            for (status, body, expected) in cases {
+                assert_eq!(
+                    DexcomError::from_response(status, body),
+                    expected,
+                    "{} {}",
+                    status,
+                    body
+                );
+            }
+        }
+    }
 }
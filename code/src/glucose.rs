@@ -0,0 +1,448 @@
+pub mod glucose {
+    use crate::server::server::{ServableData, ServableDataReq, ServableDataRsp, ServerData};
+    use crate::storage::storage::Storable;
+    use log::info;
+    use serde::{Deserialize, Serialize};
+    use std::fmt;
+    use std::sync::mpsc;
+
+    #[derive(Debug, Copy, Clone, PartialEq)]
+    pub enum GlucoseTrend {
+        NoTrend,
+        DoubleUp,
+        SingleUp,
+        FortyFiveUp,
+        Flat,
+        FortyFiveDown,
+        SingleDown,
+        DoubleDown,
+        NotComputable,
+        RateOutOfRange,
+    }
+
+    impl GlucoseTrend {
+        pub fn from_name(trend: &str) -> Self {
+            match trend {
+                "DoubleUp" => Self::DoubleUp,
+                "SingleUp" => Self::SingleUp,
+                "FortyFiveUp" => Self::FortyFiveUp,
+                "Flat" => Self::Flat,
+                "FortyFiveDown" => Self::FortyFiveDown,
+                "SingleDown" => Self::SingleDown,
+                "DoubleDown" => Self::DoubleDown,
+                "NotComputable" | "NOT COMPUTABLE" => Self::NotComputable,
+                "RateOutOfRange" | "RATE OUT OF RANGE" => Self::RateOutOfRange,
+                _ => Self::NoTrend,
+            }
+        }
+
+        pub fn from_index(trend: u8) -> Self {
+            match trend {
+                1 => Self::DoubleUp,
+                2 => Self::SingleUp,
+                3 => Self::FortyFiveUp,
+                4 => Self::Flat,
+                5 => Self::FortyFiveDown,
+                6 => Self::SingleDown,
+                7 => Self::DoubleDown,
+                8 => Self::NotComputable,
+                9 => Self::RateOutOfRange,
+                _ => Self::NoTrend,
+            }
+        }
+
+        // Arrows of travel, negative when falling. None if there's no usable trend.
+        pub fn direction(&self) -> Option<isize> {
+            match self {
+                Self::DoubleUp => Some(3),
+                Self::SingleUp => Some(2),
+                Self::FortyFiveUp => Some(1),
+                Self::Flat => Some(0),
+                Self::FortyFiveDown => Some(-1),
+                Self::SingleDown => Some(-2),
+                Self::DoubleDown => Some(-3),
+                Self::NoTrend | Self::NotComputable | Self::RateOutOfRange => None,
+            }
+        }
+    }
+
+    // 1 mmol/L of glucose is this many mg/dL
+    pub const MGDL_PER_MMOL: f32 = 18.0182;
+
+    #[derive(Deserialize, Serialize, Debug, Default, Copy, Clone, PartialEq)]
+    #[serde(rename_all = "lowercase")]
+    pub enum GlucoseUnit {
+        #[default]
+        MgDl,
+        Mmol,
+    }
+
+    impl GlucoseUnit {
+        pub fn convert(self, mgdl: isize) -> f32 {
+            match self {
+                Self::MgDl => mgdl as f32,
+                // mmol/L is only ever shown to one decimal place
+                Self::Mmol => (mgdl as f32 / MGDL_PER_MMOL * 10.0).round() / 10.0,
+            }
+        }
+
+        pub fn to_mgdl(self, value: f32) -> isize {
+            match self {
+                Self::MgDl => value.round() as isize,
+                Self::Mmol => (value * MGDL_PER_MMOL).round() as isize,
+            }
+        }
+    }
+
+    // A glucose level entered by the user, either as a bare number in the display
+    // unit or as {"value": 5.5, "unit": "mmol"}
+    #[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq)]
+    #[serde(untagged)]
+    pub enum GlucoseLevel {
+        Bare(f32),
+        WithUnit { value: f32, unit: GlucoseUnit },
+    }
+
+    impl GlucoseLevel {
+        pub fn to_mgdl(self, display_unit: GlucoseUnit) -> isize {
+            match self {
+                Self::Bare(value) => display_unit.to_mgdl(value),
+                Self::WithUnit { value, unit } => unit.to_mgdl(value),
+            }
+        }
+    }
+
+    #[derive(Debug, Copy, Clone)]
+    pub struct GlucoseReading {
+        pub time: i64,
+        // Always in mg/dL, convert with in_unit() for display
+        pub value: isize,
+        pub trend: GlucoseTrend,
+        // Timezone offsets of the sensor (ST) and display device (DT), in minutes
+        pub system_offset: Option<i16>,
+        pub display_offset: Option<i16>,
+    }
+
+    impl Default for GlucoseReading {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl GlucoseReading {
+        pub fn new() -> Self {
+            Self {
+                time: 0,
+                value: 0,
+                trend: GlucoseTrend::NoTrend,
+                system_offset: None,
+                display_offset: None,
+            }
+        }
+
+        pub fn in_unit(&self, unit: GlucoseUnit) -> f32 {
+            unit.convert(self.value)
+        }
+    }
+
+    // Backend-agnostic failures, classified by what the state machine has to do
+    // about them
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum GlucoseError {
+        NoCredentials,
+        AuthFailed(String),
+        SessionLost(String),
+        RateLimited,
+        NoReadings,
+        // Logged in fine, but nobody shares their readings with the account
+        NotShared,
+        Unavailable(String),
+    }
+
+    impl GlucoseError {
+        // Errors that new credentials are needed to get past
+        pub fn is_auth_failure(&self) -> bool {
+            matches!(self, Self::NoCredentials | Self::AuthFailed(_))
+        }
+
+        // Errors that logging in again will fix
+        pub fn is_session_failure(&self) -> bool {
+            matches!(self, Self::SessionLost(_))
+        }
+    }
+
+    impl fmt::Display for GlucoseError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                Self::NoCredentials => write!(f, "no credentials stored"),
+                Self::AuthFailed(e) => write!(f, "authentication failed: {}", e),
+                Self::SessionLost(e) => write!(f, "session lost: {}", e),
+                Self::RateLimited => write!(f, "rate limited"),
+                Self::NoReadings => write!(f, "no glucose readings available"),
+                Self::NotShared => write!(f, "no readings shared with this account"),
+                Self::Unavailable(e) => write!(f, "source unavailable: {}", e),
+            }
+        }
+    }
+
+    impl std::error::Error for GlucoseError {}
+
+    // Anything the lamp can get glucose readings from
+    pub trait GlucoseSource {
+        fn has_creds(&self) -> bool;
+        fn reset_creds(&mut self);
+        fn connect(&mut self) -> Result<(), GlucoseError>;
+        fn get_latest_glucose(&mut self) -> Result<GlucoseReading, GlucoseError>;
+        // Every reading from the last 24 hours, newest first
+        fn get_glucose_history(&mut self) -> Result<Vec<GlucoseReading>, GlucoseError>;
+    }
+
+    #[derive(Deserialize, Serialize, Debug, Default, Copy, Clone, PartialEq)]
+    #[serde(rename_all = "lowercase")]
+    pub enum SourceKind {
+        #[default]
+        Dexcom,
+        Nightscout,
+        Libre,
+    }
+
+    // Stores which backend the lamp should read from
+    pub struct SourceSelect {
+        kind: SourceKind,
+        server_channel: Option<mpsc::Receiver<ServableDataReq>>,
+        save_data: bool,
+    }
+
+    impl Default for SourceSelect {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl SourceSelect {
+        pub fn new() -> Self {
+            SourceSelect {
+                kind: SourceKind::default(),
+                server_channel: None,
+                save_data: false,
+            }
+        }
+
+        pub fn kind(&self) -> SourceKind {
+            self.kind
+        }
+
+        pub fn need_to_save(&self) -> bool {
+            self.save_data
+        }
+
+        pub fn saved(&mut self) {
+            self.save_data = false;
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct NvsSourceState {
+        kind: SourceKind,
+    }
+
+    impl Storable for SourceSelect {
+        fn store_tag(&self) -> &str {
+            "glucose_source"
+        }
+
+        fn store_data(&self) -> Vec<u8> {
+            let data = NvsSourceState { kind: self.kind };
+
+            serde_json::to_string(&data).unwrap().into_bytes()
+        }
+
+        fn recall_data(&mut self, data: &[u8]) {
+            let nvs_state = serde_json::from_slice::<NvsSourceState>(data).unwrap();
+            self.kind = nvs_state.kind;
+            self.save_data = false;
+        }
+    }
+
+    impl ServableData for SourceSelect {
+        fn get_channel(&mut self) -> mpsc::Sender<ServableDataReq> {
+            let (tx, rx) = mpsc::channel::<ServableDataReq>();
+            self.server_channel = Some(rx);
+            tx
+        }
+
+        fn handle_server_req(&mut self) {
+            if let Some(channel) = &self.server_channel {
+                if let Ok(req) = channel.try_recv() {
+                    info!("source select got a request from server");
+
+                    if let ServableDataReq::Get(back_channel) = &req {
+                        info!("Sending source state to server");
+                        let mut rsp = ServerData::new();
+                        rsp.glucose_source = Some(self.kind);
+                        back_channel.send(ServableDataRsp::Data(rsp)).unwrap();
+                    }
+
+                    if let ServableDataReq::Set(update) = &req {
+                        if let Some(kind) = &update.glucose_source {
+                            self.kind = *kind;
+                            self.save_data = true;
+                        }
+                    }
+
+                    if let ServableDataReq::Reset = &req {
+                        self.kind = SourceKind::default();
+                        self.save_data = true;
+                    }
+                }
+            }
+        }
+    }
+
+    // Stores which unit readings and thresholds are shown in
+    pub struct UnitSelect {
+        unit: GlucoseUnit,
+        server_channel: Option<mpsc::Receiver<ServableDataReq>>,
+        save_data: bool,
+    }
+
+    impl Default for UnitSelect {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl UnitSelect {
+        pub fn new() -> Self {
+            UnitSelect {
+                unit: GlucoseUnit::default(),
+                server_channel: None,
+                save_data: false,
+            }
+        }
+
+        pub fn unit(&self) -> GlucoseUnit {
+            self.unit
+        }
+
+        pub fn need_to_save(&self) -> bool {
+            self.save_data
+        }
+
+        pub fn saved(&mut self) {
+            self.save_data = false;
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct NvsUnitState {
+        unit: GlucoseUnit,
+    }
+
+    impl Storable for UnitSelect {
+        fn store_tag(&self) -> &str {
+            "glucose_unit"
+        }
+
+        fn store_data(&self) -> Vec<u8> {
+            let data = NvsUnitState { unit: self.unit };
+
+            serde_json::to_string(&data).unwrap().into_bytes()
+        }
+
+        fn recall_data(&mut self, data: &[u8]) {
+            let nvs_state = serde_json::from_slice::<NvsUnitState>(data).unwrap();
+            self.unit = nvs_state.unit;
+            self.save_data = false;
+        }
+    }
+
+    impl ServableData for UnitSelect {
+        fn get_channel(&mut self) -> mpsc::Sender<ServableDataReq> {
+            let (tx, rx) = mpsc::channel::<ServableDataReq>();
+            self.server_channel = Some(rx);
+            tx
+        }
+
+        fn handle_server_req(&mut self) {
+            if let Some(channel) = &self.server_channel {
+                if let Ok(req) = channel.try_recv() {
+                    info!("unit select got a request from server");
+
+                    if let ServableDataReq::Get(back_channel) = &req {
+                        info!("Sending unit state to server");
+                        let mut rsp = ServerData::new();
+                        rsp.glucose_unit = Some(self.unit);
+                        back_channel.send(ServableDataRsp::Data(rsp)).unwrap();
+                    }
+
+                    if let ServableDataReq::Set(update) = &req {
+                        if let Some(unit) = &update.glucose_unit {
+                            self.unit = *unit;
+                            self.save_data = true;
+                        }
+                    }
+
+                    if let ServableDataReq::Reset = &req {
+                        self.unit = GlucoseUnit::default();
+                        self.save_data = true;
+                    }
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn converts_to_mmol_to_one_decimal() {
+            assert_eq!(GlucoseUnit::Mmol.convert(100), 5.5);
+            assert_eq!(GlucoseUnit::Mmol.convert(70), 3.9);
+            assert_eq!(GlucoseUnit::Mmol.convert(180), 10.0);
+            assert_eq!(GlucoseUnit::Mmol.convert(39), 2.2);
+            assert_eq!(GlucoseUnit::MgDl.convert(123), 123.0);
+        }
+
+        #[test]
+        fn converts_to_whole_mgdl() {
+            assert_eq!(GlucoseUnit::Mmol.to_mgdl(5.5), 99);
+            assert_eq!(GlucoseUnit::Mmol.to_mgdl(3.9), 70);
+            assert_eq!(GlucoseUnit::Mmol.to_mgdl(10.0), 180);
+            assert_eq!(GlucoseUnit::MgDl.to_mgdl(120.4), 120);
+            assert_eq!(GlucoseUnit::MgDl.to_mgdl(120.5), 121);
+        }
+
+        #[test]
+        fn round_trips() {
+            // Every mmol/L value the lamp can show survives the trip through mg/dL
+            for tenths in 22..=222 {
+                let mmol = tenths as f32 / 10.0;
+                let mgdl = GlucoseUnit::Mmol.to_mgdl(mmol);
+                assert_eq!(GlucoseUnit::Mmol.convert(mgdl), mmol);
+            }
+
+            // Going the other way loses at most the rounding to one decimal
+            for mgdl in 39..=401 {
+                let mmol = GlucoseUnit::Mmol.convert(mgdl);
+                assert!((GlucoseUnit::Mmol.to_mgdl(mmol) - mgdl).abs() <= 1);
+                assert_eq!(
+                    GlucoseUnit::MgDl.to_mgdl(GlucoseUnit::MgDl.convert(mgdl)),
+                    mgdl
+                );
+            }
+        }
+
+        #[test]
+        fn levels_use_their_own_unit() {
+            let bare: GlucoseLevel = serde_json::from_str("5.5").unwrap();
+            let tagged: GlucoseLevel =
+                serde_json::from_str(r#"{"value": 5.5, "unit": "mmol"}"#).unwrap();
+
+            assert_eq!(bare.to_mgdl(GlucoseUnit::Mmol), 99);
+            assert_eq!(bare.to_mgdl(GlucoseUnit::MgDl), 6);
+            assert_eq!(tagged.to_mgdl(GlucoseUnit::MgDl), 99);
+        }
+    }
+}
@@ -0,0 +1,407 @@
+pub mod graph {
+    use crate::colormap::colormap::ColorMap;
+    use crate::history::history::{GlucoseHistory, HISTORY_SPAN_MS};
+    use crate::lamp::lamp::{LedState, BLACK};
+    use crate::server::server::{ServableData, ServableDataReq, ServableDataRsp, ServerData};
+    use crate::storage::storage::Storable;
+    use log::info;
+    use rgb_led::{DEFAULT_LED_COUNT, RGB8};
+    use serde::{Deserialize, Serialize};
+    use std::sync::mpsc;
+
+    // Buckets slide along as time passes even without new readings, so redraw
+    // at least this often, in seconds
+    const REDRAW_INTERVAL: u64 = 60;
+    // Anything shorter than a couple of readings per strip isn't a graph
+    pub const MIN_HOURS: f32 = 0.25;
+    pub const MAX_HOURS: f32 = (HISTORY_SPAN_MS / (60 * 60 * 1000)) as f32;
+
+    #[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq)]
+    #[serde(default)]
+    pub struct GraphSettings {
+        pub enabled: bool,
+        // How much history the whole graph covers
+        pub hours: f32,
+        // LEDs before this one keep showing the main lamp color
+        pub first_led: usize,
+        // Put the newest bucket next to the lamp rather than at the far end
+        pub newest_first: bool,
+    }
+
+    impl GraphSettings {
+        pub fn clamped(&self) -> Self {
+            GraphSettings {
+                hours: self.hours.clamp(MIN_HOURS, MAX_HOURS),
+                ..*self
+            }
+        }
+    }
+
+    impl Default for GraphSettings {
+        fn default() -> Self {
+            // Start on the first LED past the ones on the lamp board
+            GraphSettings {
+                enabled: false,
+                hours: 3.0,
+                first_led: DEFAULT_LED_COUNT,
+                newest_first: true,
+            }
+        }
+    }
+
+    // Average value, in mg/dL, of the readings in each of `count` equal slices of
+    // the `span_ms` before `now`, newest first. None where there were no readings.
+    pub fn buckets(
+        history: &GlucoseHistory,
+        now: i64,
+        span_ms: i64,
+        count: usize,
+    ) -> Vec<Option<isize>> {
+        if count == 0 {
+            return Vec::new();
+        }
+        let mut sums = vec![(0isize, 0isize); count];
+        let bucket_ms = (span_ms / count as i64).max(1);
+
+        for reading in history.since(now - span_ms) {
+            let idx = ((now - reading.time).max(0) / bucket_ms) as usize;
+            if let Some((sum, readings)) = sums.get_mut(idx) {
+                *sum += reading.value;
+                *readings += 1;
+            }
+        }
+
+        sums.into_iter()
+            .map(|(sum, readings)| (readings > 0).then(|| sum / readings))
+            .collect()
+    }
+
+    // Shows recent history as a bar of colors along an LED strip
+    pub struct HistoryGraph {
+        settings: GraphSettings,
+        // Uptime, newest reading time and strip length of the last draw
+        drawn: Option<(u64, Option<i64>, usize)>,
+        server_channel: Option<mpsc::Receiver<ServableDataReq>>,
+        save_data: bool,
+    }
+
+    impl Default for HistoryGraph {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl HistoryGraph {
+        pub fn new() -> Self {
+            HistoryGraph {
+                settings: GraphSettings::default(),
+                drawn: None,
+                server_channel: None,
+                save_data: false,
+            }
+        }
+
+        pub fn settings(&self) -> &GraphSettings {
+            &self.settings
+        }
+
+        pub fn set_settings(&mut self, settings: GraphSettings) {
+            self.settings = settings.clamped();
+            self.drawn = None;
+            self.save_data = true;
+        }
+
+        // Redraw on the next update, e.g. because the colors of the bars changed
+        pub fn invalidate(&mut self) {
+            self.drawn = None;
+        }
+
+        // Per-LED colors for a strip of `led_count` LEDs, None for LEDs that
+        // should show the main lamp color. Empty if the graph is off.
+        pub fn pixels(
+            &self,
+            history: &GlucoseHistory,
+            color_map: &ColorMap,
+            wall_now: Option<i64>,
+            led_count: usize,
+        ) -> Vec<Option<RGB8>> {
+            // Without a clock, the newest reading is the best guess at now
+            let Some(now) = wall_now.or(history.latest().map(|r| r.time)) else {
+                return Vec::new();
+            };
+            if !self.settings.enabled || led_count <= self.settings.first_led {
+                return Vec::new();
+            }
+
+            let span_ms = (self.settings.hours * 60.0 * 60.0 * 1000.0) as i64;
+            let mut bars: Vec<Option<RGB8>> =
+                buckets(history, now, span_ms, led_count - self.settings.first_led)
+                    .into_iter()
+                    .map(|value| Some(value.map_or(BLACK, |value| bar_color(color_map, value))))
+                    .collect();
+            if !self.settings.newest_first {
+                bars.reverse();
+            }
+
+            let mut pixels = vec![None; self.settings.first_led];
+            pixels.extend(bars);
+            pixels
+        }
+
+        // New pixels if the graph needs redrawing, call this from the main loop.
+        // `now` is the uptime in seconds.
+        pub fn update(
+            &mut self,
+            now: u64,
+            wall_now: Option<i64>,
+            history: &GlucoseHistory,
+            color_map: &ColorMap,
+            led_count: usize,
+        ) -> Option<Vec<Option<RGB8>>> {
+            let newest = history.latest().map(|r| r.time);
+            if let Some((drawn_at, drawn_newest, drawn_count)) = self.drawn {
+                if drawn_newest == newest
+                    && drawn_count == led_count
+                    && now < drawn_at + REDRAW_INTERVAL
+                {
+                    return None;
+                }
+            }
+
+            self.drawn = Some((now, newest, led_count));
+            Some(self.pixels(history, color_map, wall_now, led_count))
+        }
+
+        pub fn need_to_save(&self) -> bool {
+            self.save_data
+        }
+
+        pub fn saved(&mut self) {
+            self.save_data = false;
+        }
+    }
+
+    // Bars are steady, even for values the lamp itself breathes for
+    fn bar_color(color_map: &ColorMap, value: isize) -> RGB8 {
+        match color_map.led_state(value) {
+            LedState::Steady(color) | LedState::Breathe(color) => color,
+            _ => BLACK,
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct NvsGraphState {
+        settings: GraphSettings,
+    }
+
+    impl Storable for HistoryGraph {
+        fn store_tag(&self) -> &str {
+            "history_graph"
+        }
+
+        fn store_data(&self) -> Vec<u8> {
+            let data = NvsGraphState {
+                settings: self.settings,
+            };
+
+            serde_json::to_string(&data).unwrap().into_bytes()
+        }
+
+        fn recall_data(&mut self, data: &[u8]) {
+            let nvs_state = serde_json::from_slice::<NvsGraphState>(data).unwrap();
+            self.settings = nvs_state.settings.clamped();
+            self.drawn = None;
+            self.save_data = false;
+        }
+    }
+
+    impl ServableData for HistoryGraph {
+        fn get_channel(&mut self) -> mpsc::Sender<ServableDataReq> {
+            let (tx, rx) = mpsc::channel::<ServableDataReq>();
+            self.server_channel = Some(rx);
+            tx
+        }
+
+        fn handle_server_req(&mut self) {
+            if let Some(channel) = &self.server_channel {
+                if let Ok(req) = channel.try_recv() {
+                    info!("history graph got a request from server");
+
+                    if let ServableDataReq::Get(back_channel) = &req {
+                        info!("Sending history graph settings to server");
+                        let mut rsp = ServerData::new();
+                        rsp.history_graph = Some(self.settings);
+                        back_channel.send(ServableDataRsp::Data(rsp)).unwrap();
+                    }
+
+                    if let ServableDataReq::Set(update) = &req {
+                        if let Some(settings) = &update.history_graph {
+                            self.set_settings(*settings);
+                        }
+                    }
+
+                    if let ServableDataReq::Reset = &req {
+                        self.set_settings(GraphSettings::default());
+                    }
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::glucose::glucose::GlucoseReading;
+
+        fn history() -> GlucoseHistory {
+            let mut history = GlucoseHistory::new();
+            history.insert(GlucoseReading {
+                time: 1_000_000,
+                value: 100,
+                ..GlucoseReading::new()
+            });
+            history
+        }
+
+        const NOW: i64 = 1_700_000_000_000;
+        const MINUTE: i64 = 60 * 1000;
+
+        fn readings(readings: &[(i64, isize)]) -> GlucoseHistory {
+            let mut history = GlucoseHistory::new();
+            for &(minutes_ago, value) in readings {
+                history.insert(GlucoseReading {
+                    time: NOW - minutes_ago * MINUTE,
+                    value,
+                    ..GlucoseReading::new()
+                });
+            }
+            history
+        }
+
+        fn enabled() -> HistoryGraph {
+            let mut graph = HistoryGraph::new();
+            graph.set_settings(GraphSettings {
+                enabled: true,
+                first_led: 2,
+                ..GraphSettings::default()
+            });
+            graph
+        }
+
+        #[test]
+        fn buckets_split_the_span_evenly() {
+            // Four 15 minute buckets, each covering up to but not including its far edge
+            let history = readings(&[(60, 200), (45, 130), (30, 120), (15, 110), (0, 100)]);
+
+            assert_eq!(
+                buckets(&history, NOW, 60 * MINUTE, 4),
+                [Some(100), Some(110), Some(120), Some(130)]
+            );
+            // Readings just inside the far edge still count, and ones stamped
+            // slightly after now land in the newest bucket
+            assert_eq!(
+                buckets(&history, NOW - 1, 60 * MINUTE, 4),
+                [Some(105), Some(120), Some(130), Some(200)]
+            );
+        }
+
+        #[test]
+        fn buckets_average_their_readings() {
+            let history = readings(&[(14, 100), (10, 103), (5, 120), (20, 90)]);
+
+            // Averages round down to whole mg/dL
+            assert_eq!(
+                buckets(&history, NOW, 30 * MINUTE, 2),
+                [Some(107), Some(90)]
+            );
+        }
+
+        #[test]
+        fn empty_buckets_are_gaps() {
+            let history = readings(&[(55, 150), (5, 100)]);
+
+            assert_eq!(
+                buckets(&history, NOW, 60 * MINUTE, 4),
+                [Some(100), None, None, Some(150)]
+            );
+            assert_eq!(
+                buckets(&GlucoseHistory::new(), NOW, 60 * MINUTE, 2),
+                [None, None]
+            );
+            assert!(buckets(&history, NOW, 60 * MINUTE, 0).is_empty());
+        }
+
+        #[test]
+        fn oldest_first_reverses_the_bars() {
+            let history = readings(&[(50, 60), (35, 150), (20, 260), (5, 400)]);
+            let color_map = ColorMap::new();
+            let mut graph = HistoryGraph::new();
+            graph.set_settings(GraphSettings {
+                enabled: true,
+                hours: 1.0,
+                first_led: 1,
+                newest_first: true,
+            });
+
+            let newest_first = graph.pixels(&history, &color_map, Some(NOW), 5);
+            let bars: Vec<_> = [400, 260, 150, 60]
+                .into_iter()
+                .map(|value| Some(bar_color(&color_map, value)))
+                .collect();
+            assert_eq!(newest_first[0], None);
+            assert_eq!(newest_first[1..], bars);
+
+            graph.set_settings(GraphSettings {
+                newest_first: false,
+                ..*graph.settings()
+            });
+            let oldest_first = graph.pixels(&history, &color_map, Some(NOW), 5);
+            assert_eq!(oldest_first[0], None);
+            assert!(oldest_first[1..].iter().eq(bars.iter().rev()));
+        }
+
+        #[test]
+        fn only_redraws_when_something_changed() {
+            let mut graph = enabled();
+            let history = history();
+            let color_map = ColorMap::new();
+
+            let pixels = graph.update(0, None, &history, &color_map, 6).unwrap();
+            assert_eq!(pixels.len(), 6);
+            assert_eq!(pixels[..2], [None, None]);
+            assert_eq!(pixels[2], Some(bar_color(&color_map, 100)));
+
+            assert!(graph.update(1, None, &history, &color_map, 6).is_none());
+            assert!(graph.update(2, None, &history, &color_map, 8).is_some());
+            assert!(graph
+                .update(2 + REDRAW_INTERVAL, None, &history, &color_map, 8)
+                .is_some());
+        }
+
+        #[test]
+        fn redraws_after_invalidate() {
+            let mut graph = enabled();
+            let history = history();
+            let mut color_map = ColorMap::new();
+            graph.update(0, None, &history, &color_map, 6);
+
+            let mut stops = color_map.stops().to_vec();
+            for stop in stops.iter_mut() {
+                stop.color = [10, 20, 30];
+            }
+            color_map.set_stops(stops).unwrap();
+            graph.invalidate();
+
+            let pixels = graph.update(1, None, &history, &color_map, 6).unwrap();
+            assert_eq!(pixels[2], Some(RGB8::new(10, 20, 30)));
+        }
+
+        #[test]
+        fn disabled_graph_leaves_the_strip_alone() {
+            let mut graph = HistoryGraph::new();
+
+            let pixels = graph.update(0, None, &history(), &ColorMap::new(), 16);
+            assert_eq!(pixels, Some(Vec::new()));
+        }
+    }
+}
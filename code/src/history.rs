@@ -0,0 +1,250 @@
+pub mod history {
+    use crate::glucose::glucose::{GlucoseReading, GlucoseUnit};
+    use crate::server::server::{ServableData, ServableDataReq, ServableDataRsp, ServerData};
+    use log::info;
+    use std::collections::VecDeque;
+    use std::sync::mpsc;
+
+    // Keep a day of readings, measured back from the newest one
+    pub const HISTORY_SPAN_MS: i64 = 24 * 60 * 60 * 1000;
+    // One reading every 5 minutes for 24 hours, plus some slack for early readings
+    pub const HISTORY_CAPACITY: usize = 300;
+    // CGMs produce a reading every 5 minutes, anything much longer is a gap
+    pub const READING_INTERVAL_MS: i64 = 5 * 60 * 1000;
+    pub const GAP_THRESHOLD_MS: i64 = READING_INTERVAL_MS * 3 / 2;
+
+    #[derive(Debug, Copy, Clone, PartialEq)]
+    pub struct GlucoseGap {
+        // Times of the readings either side of the gap, in ms since the Unix epoch
+        pub start: i64,
+        pub end: i64,
+    }
+
+    impl GlucoseGap {
+        // Roughly how many readings are missing
+        pub fn missing(&self) -> usize {
+            ((self.end - self.start) / READING_INTERVAL_MS - 1).max(0) as usize
+        }
+    }
+
+    // Recent readings, oldest first, with at most one reading per timestamp
+    pub struct GlucoseHistory {
+        readings: VecDeque<GlucoseReading>,
+        // Unit readings are reported to the server in
+        unit: GlucoseUnit,
+        server_channel: Option<mpsc::Receiver<ServableDataReq>>,
+    }
+
+    impl Default for GlucoseHistory {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl GlucoseHistory {
+        pub fn new() -> Self {
+            GlucoseHistory {
+                readings: VecDeque::with_capacity(HISTORY_CAPACITY),
+                unit: GlucoseUnit::default(),
+                server_channel: None,
+            }
+        }
+
+        // Add a reading, returns false if one with the same timestamp was already there
+        // or it is too old to keep
+        pub fn insert(&mut self, reading: GlucoseReading) -> bool {
+            // Readings almost always arrive in order, so search from the back
+            let idx = self
+                .readings
+                .iter()
+                .rposition(|r| r.time <= reading.time)
+                .map(|idx| idx + 1)
+                .unwrap_or(0);
+
+            if idx > 0 && self.readings[idx - 1].time == reading.time {
+                return false;
+            }
+
+            if let Some(newest) = self.latest() {
+                if reading.time < newest.time - HISTORY_SPAN_MS {
+                    return false;
+                }
+            }
+
+            self.readings.insert(idx, reading);
+            // A full history drops its oldest reading, which may be this one
+            idx >= self.prune()
+        }
+
+        // Add a batch of readings in any order, returns how many were new
+        pub fn extend<I: IntoIterator<Item = GlucoseReading>>(&mut self, readings: I) -> usize {
+            readings
+                .into_iter()
+                .filter(|reading| self.insert(*reading))
+                .count()
+        }
+
+        pub fn set_unit(&mut self, unit: GlucoseUnit) {
+            self.unit = unit;
+        }
+
+        pub fn clear(&mut self) {
+            self.readings.clear();
+        }
+
+        pub fn len(&self) -> usize {
+            self.readings.len()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.readings.is_empty()
+        }
+
+        pub fn latest(&self) -> Option<&GlucoseReading> {
+            self.readings.back()
+        }
+
+        pub fn oldest(&self) -> Option<&GlucoseReading> {
+            self.readings.front()
+        }
+
+        // Oldest first
+        pub fn iter(&self) -> impl DoubleEndedIterator<Item = &GlucoseReading> {
+            self.readings.iter()
+        }
+
+        // Readings taken at or after `time`, oldest first
+        pub fn since(&self, time: i64) -> impl Iterator<Item = &GlucoseReading> {
+            self.readings.iter().skip_while(move |r| r.time < time)
+        }
+
+        // Stretches between consecutive readings where at least one reading is missing
+        pub fn gaps(&self) -> Vec<GlucoseGap> {
+            self.readings
+                .iter()
+                .zip(self.readings.iter().skip(1))
+                .filter(|(a, b)| b.time - a.time > GAP_THRESHOLD_MS)
+                .map(|(a, b)| GlucoseGap {
+                    start: a.time,
+                    end: b.time,
+                })
+                .collect()
+        }
+
+        // Drop everything older than a day before the newest reading, returns how
+        // many readings went
+        fn prune(&mut self) -> usize {
+            let Some(newest) = self.latest().map(|r| r.time) else {
+                return 0;
+            };
+
+            let mut dropped = 0;
+            while let Some(oldest) = self.readings.front() {
+                if oldest.time < newest - HISTORY_SPAN_MS || self.readings.len() > HISTORY_CAPACITY
+                {
+                    self.readings.pop_front();
+                    dropped += 1;
+                } else {
+                    break;
+                }
+            }
+
+            dropped
+        }
+    }
+
+    impl ServableData for GlucoseHistory {
+        fn get_channel(&mut self) -> mpsc::Sender<ServableDataReq> {
+            let (tx, rx) = mpsc::channel::<ServableDataReq>();
+            self.server_channel = Some(rx);
+            tx
+        }
+
+        fn handle_server_req(&mut self) {
+            if let Some(channel) = &self.server_channel {
+                if let Ok(req) = channel.try_recv() {
+                    info!("history got a request from server");
+
+                    if let ServableDataReq::Get(back_channel) = &req {
+                        info!("Sending history state to server");
+                        let mut rsp = ServerData::new();
+                        rsp.history_len = Some(self.len());
+                        rsp.history_gaps = Some(self.gaps().len());
+                        rsp.last_reading_time = self.latest().map(|r| r.time);
+                        rsp.glucose = self.latest().map(|r| r.in_unit(self.unit));
+                        back_channel.send(ServableDataRsp::Data(rsp)).unwrap();
+                    }
+
+                    if let ServableDataReq::Reset = &req {
+                        self.clear();
+                    }
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn reading(time: i64) -> GlucoseReading {
+            GlucoseReading {
+                time,
+                value: 100,
+                ..GlucoseReading::new()
+            }
+        }
+
+        #[test]
+        fn keeps_readings_in_order_without_duplicates() {
+            let mut history = GlucoseHistory::new();
+
+            assert!(history.insert(reading(2 * READING_INTERVAL_MS)));
+            assert!(history.insert(reading(0)));
+            assert!(history.insert(reading(READING_INTERVAL_MS)));
+            assert!(!history.insert(reading(READING_INTERVAL_MS)));
+
+            let times: Vec<i64> = history.iter().map(|r| r.time).collect();
+            assert_eq!(times, [0, READING_INTERVAL_MS, 2 * READING_INTERVAL_MS]);
+        }
+
+        #[test]
+        fn refuses_readings_older_than_the_span() {
+            let mut history = GlucoseHistory::new();
+            history.insert(reading(HISTORY_SPAN_MS + READING_INTERVAL_MS));
+
+            assert!(!history.insert(reading(0)));
+            assert_eq!(history.len(), 1);
+        }
+
+        #[test]
+        fn newer_readings_push_out_old_ones() {
+            let mut history = GlucoseHistory::new();
+            history.insert(reading(0));
+
+            assert!(history.insert(reading(HISTORY_SPAN_MS + READING_INTERVAL_MS)));
+            assert_eq!(history.len(), 1);
+        }
+
+        #[test]
+        fn full_history_refuses_a_reading_it_would_drop_straight_away() {
+            let mut history = GlucoseHistory::new();
+            let start = 1_000 * READING_INTERVAL_MS;
+            let added = history.extend(
+                (0..HISTORY_CAPACITY as i64)
+                    .map(|idx| reading(start + idx * READING_INTERVAL_MS / 2)),
+            );
+            assert_eq!(added, HISTORY_CAPACITY);
+
+            // Within the span, but older than everything in a full history
+            assert!(!history.insert(reading(start - READING_INTERVAL_MS)));
+            assert_eq!(history.len(), HISTORY_CAPACITY);
+            assert_eq!(history.oldest().unwrap().time, start);
+
+            // Newer ones still go in, at the expense of the oldest
+            assert!(history.insert(reading(start + 1)));
+            assert_eq!(history.len(), HISTORY_CAPACITY);
+            assert_eq!(history.oldest().unwrap().time, start + 1);
+        }
+    }
+}
@@ -0,0 +1,486 @@
+pub mod http {
+    use serde::de::DeserializeOwned;
+    use std::collections::VecDeque;
+    use std::fmt;
+    use std::io::{self, BufReader, Read};
+
+    // Largest body we're willing to read. A full day of Share readings is
+    // roughly 40kB.
+    pub const MAX_BODY_LEN: usize = 64 * 1024;
+    // How much of the body is pulled off the connection at a time
+    pub const READ_CHUNK_LEN: usize = 1024;
+
+    // A response whose body is still on the connection, so it can be parsed as
+    // it comes in rather than held in full first
+    pub struct HttpResponse<'a> {
+        pub status: u16,
+        body: BodyReader<Box<dyn Read + 'a>>,
+    }
+
+    impl<'a> HttpResponse<'a> {
+        // `expected` is the Content-Length, if the server sent one
+        pub fn new(status: u16, body: impl Read + 'a, expected: Option<usize>) -> Self {
+            Self {
+                status,
+                body: BodyReader::new(Box::new(body), expected),
+            }
+        }
+
+        pub fn json<D: DeserializeOwned>(self) -> Result<D, BodyError> {
+            let reader = BufReader::with_capacity(READ_CHUNK_LEN, self.body);
+            serde_json::from_reader(reader).map_err(BodyError::from)
+        }
+
+        // The whole body, for error responses and other small bodies
+        pub fn text(mut self) -> Result<String, BodyError> {
+            let mut text = String::new();
+            self.body.read_to_string(&mut text)?;
+            Ok(text)
+        }
+    }
+
+    // The body didn't arrive in full, either because it was larger than
+    // MAX_BODY_LEN or because the connection closed before Content-Length was met
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct TruncatedBody {
+        pub read: usize,
+        pub expected: Option<usize>,
+    }
+
+    impl fmt::Display for TruncatedBody {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self.expected {
+                Some(expected) => {
+                    write!(f, "body truncated at {} of {} bytes", self.read, expected)
+                }
+                None => write!(f, "body truncated at {} bytes", self.read),
+            }
+        }
+    }
+
+    impl std::error::Error for TruncatedBody {}
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum BodyError {
+        Truncated(TruncatedBody),
+        // Not the JSON or text we were after, or the connection failed part way
+        Malformed(String),
+    }
+
+    impl fmt::Display for BodyError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                Self::Truncated(truncated) => write!(f, "{}", truncated),
+                Self::Malformed(e) => write!(f, "{}", e),
+            }
+        }
+    }
+
+    impl std::error::Error for BodyError {}
+
+    impl From<io::Error> for BodyError {
+        fn from(error: io::Error) -> Self {
+            match error
+                .get_ref()
+                .and_then(|inner| inner.downcast_ref::<TruncatedBody>())
+            {
+                Some(truncated) => Self::Truncated(truncated.clone()),
+                None => Self::Malformed(error.to_string()),
+            }
+        }
+    }
+
+    impl From<serde_json::Error> for BodyError {
+        fn from(error: serde_json::Error) -> Self {
+            if error.is_io() {
+                io::Error::from(error).into()
+            } else {
+                Self::Malformed(error.to_string())
+            }
+        }
+    }
+
+    // Counts the body as it's read, and fails it if it runs past MAX_BODY_LEN or
+    // ends before the Content-Length it promised
+    pub struct BodyReader<R: Read> {
+        inner: R,
+        read: usize,
+        expected: Option<usize>,
+    }
+
+    impl<R: Read> BodyReader<R> {
+        pub fn new(inner: R, expected: Option<usize>) -> Self {
+            Self {
+                inner,
+                read: 0,
+                expected,
+            }
+        }
+
+        fn truncated(&self) -> io::Error {
+            io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                TruncatedBody {
+                    read: self.read,
+                    expected: self.expected,
+                },
+            )
+        }
+    }
+
+    impl<R: Read> Read for BodyReader<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            // No point starting on a body that's too big to finish
+            if self.expected.is_some_and(|len| len > MAX_BODY_LEN) {
+                return Err(self.truncated());
+            }
+
+            let bytes_read = self.inner.read(buf)?;
+            if bytes_read == 0 {
+                if self.expected.is_some_and(|len| self.read < len) {
+                    return Err(self.truncated());
+                }
+                return Ok(0);
+            }
+
+            if self.read + bytes_read > MAX_BODY_LEN {
+                return Err(self.truncated());
+            }
+            self.read += bytes_read;
+
+            Ok(bytes_read)
+        }
+    }
+
+    // Anything that can make JSON requests and hand back the response. Errors are
+    // reserved for transport failures, a non-200 status is still a response.
+    pub trait HttpTransport {
+        fn post(
+            &mut self,
+            url: &str,
+            headers: &[(&str, &str)],
+            payload: &str,
+        ) -> anyhow::Result<HttpResponse<'_>>;
+        fn get(&mut self, url: &str, headers: &[(&str, &str)]) -> anyhow::Result<HttpResponse<'_>>;
+    }
+
+    struct ScriptedResponse {
+        status: u16,
+        body: Vec<u8>,
+        expected: Option<usize>,
+    }
+
+    // In-memory transport that replays canned responses in order and records every
+    // request it was given, so clients can be exercised without a network
+    pub struct ScriptedTransport {
+        responses: VecDeque<anyhow::Result<ScriptedResponse>>,
+        requests: Vec<(String, String)>,
+        headers: Vec<Vec<(String, String)>>,
+    }
+
+    impl Default for ScriptedTransport {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl ScriptedTransport {
+        pub fn new() -> Self {
+            Self {
+                responses: VecDeque::new(),
+                requests: Vec::new(),
+                headers: Vec::new(),
+            }
+        }
+
+        pub fn respond(self, status: u16, body: &str) -> Self {
+            self.respond_partial(status, body, body.len())
+        }
+
+        // A body that stops short of the `expected` Content-Length
+        pub fn respond_partial(mut self, status: u16, body: &str, expected: usize) -> Self {
+            self.responses.push_back(Ok(ScriptedResponse {
+                status,
+                body: body.as_bytes().to_vec(),
+                expected: Some(expected),
+            }));
+            self
+        }
+
+        pub fn fail(mut self, error: &str) -> Self {
+            self.responses
+                .push_back(Err(anyhow::anyhow!(error.to_owned())));
+            self
+        }
+
+        // (url, payload) of every request made so far
+        pub fn requests(&self) -> &[(String, String)] {
+            &self.requests
+        }
+
+        // Value of header `name` on the `idx`th request
+        pub fn header(&self, idx: usize, name: &str) -> Option<&str> {
+            self.headers.get(idx)?.iter().find_map(|(header, value)| {
+                header.eq_ignore_ascii_case(name).then_some(value.as_str())
+            })
+        }
+
+        pub fn remaining(&self) -> usize {
+            self.responses.len()
+        }
+    }
+
+    impl ScriptedTransport {
+        fn next_response(
+            &mut self,
+            url: &str,
+            headers: &[(&str, &str)],
+            payload: &str,
+        ) -> anyhow::Result<HttpResponse<'_>> {
+            self.requests.push((url.to_owned(), payload.to_owned()));
+            self.headers.push(
+                headers
+                    .iter()
+                    .map(|(name, value)| (name.to_string(), value.to_string()))
+                    .collect(),
+            );
+            let response = self
+                .responses
+                .pop_front()
+                .unwrap_or_else(|| Err(anyhow::anyhow!("No scripted response for {}", url)))?;
+
+            Ok(HttpResponse::new(
+                response.status,
+                io::Cursor::new(response.body),
+                response.expected,
+            ))
+        }
+    }
+
+    impl HttpTransport for ScriptedTransport {
+        fn post(
+            &mut self,
+            url: &str,
+            headers: &[(&str, &str)],
+            payload: &str,
+        ) -> anyhow::Result<HttpResponse<'_>> {
+            self.next_response(url, headers, payload)
+        }
+
+        fn get(&mut self, url: &str, headers: &[(&str, &str)]) -> anyhow::Result<HttpResponse<'_>> {
+            self.next_response(url, headers, "")
+        }
+    }
+
+    #[cfg(target_os = "espidf")]
+    pub use esp::EspTransport;
+
+    // The real thing, over the ESP-IDF HTTP client
+    #[cfg(target_os = "espidf")]
+    mod esp {
+        use super::{HttpResponse, HttpTransport};
+        use crate::time::time::set_clock_from_http_date;
+        use embedded_svc::{
+            http::{
+                client::{Client, Response},
+                Headers, Method,
+            },
+            io::{Read, Write},
+        };
+        use esp_idf_svc::http::client::{Configuration as HttpConfiguration, EspHttpConnection};
+        use log::info;
+
+        pub struct EspTransport {
+            client: Client<EspHttpConnection>,
+        }
+
+        impl EspTransport {
+            pub fn new() -> anyhow::Result<Self> {
+                let connection = EspHttpConnection::new(&HttpConfiguration {
+                    use_global_ca_store: true,
+                    crt_bundle_attach: Some(esp_idf_svc::sys::esp_crt_bundle_attach),
+                    ..Default::default()
+                })?;
+
+                Ok(EspTransport {
+                    client: Client::wrap(connection),
+                })
+            }
+        }
+
+        impl HttpTransport for EspTransport {
+            fn post(
+                &mut self,
+                url: &str,
+                headers: &[(&str, &str)],
+                payload: &str,
+            ) -> anyhow::Result<HttpResponse<'_>> {
+                let content_length_header = format!("{}", payload.len());
+                let mut all_headers = vec![
+                    ("accept-encoding", "application/json"),
+                    ("content-type", "application/json"),
+                    ("content-length", &*content_length_header),
+                ];
+                all_headers.extend_from_slice(headers);
+
+                let mut request = self.client.post(url, &all_headers)?;
+                request.write_all(payload.as_bytes())?;
+                request.flush()?;
+                info!("-> POST {}", url);
+                let response = request.submit()?;
+
+                read_response(response)
+            }
+
+            fn get(
+                &mut self,
+                url: &str,
+                headers: &[(&str, &str)],
+            ) -> anyhow::Result<HttpResponse<'_>> {
+                let mut all_headers = vec![("accept", "application/json")];
+                all_headers.extend_from_slice(headers);
+
+                let request = self.client.request(Method::Get, url, &all_headers)?;
+                info!("-> GET {}", url);
+                let response = request.submit()?;
+
+                read_response(response)
+            }
+        }
+
+        // embedded-svc has its own Read, so bridge it to std's for serde_json
+        struct EspBody<'a>(Response<&'a mut EspHttpConnection>);
+
+        impl std::io::Read for EspBody<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                self.0
+                    .read(buf)
+                    .map_err(|e| std::io::Error::other(e.to_string()))
+            }
+        }
+
+        fn read_response(
+            response: Response<&mut EspHttpConnection>,
+        ) -> anyhow::Result<HttpResponse<'_>> {
+            let status = response.status();
+            let expected = response.content_len().map(|len| len as usize);
+            info!("<- {} ({:?} bytes)", status, expected);
+
+            // Good enough to date readings with until SNTP gets through
+            if let Some(date) = response.header("Date") {
+                set_clock_from_http_date(date);
+            }
+
+            Ok(HttpResponse::new(status, EspBody(response), expected))
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        // Hands the body out a few bytes at a time, like a slow connection
+        struct Trickle<'a>(&'a [u8]);
+
+        impl Read for Trickle<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let len = buf.len().min(self.0.len()).min(3);
+                buf[..len].copy_from_slice(&self.0[..len]);
+                self.0 = &self.0[len..];
+                Ok(len)
+            }
+        }
+
+        fn response(body: &[u8], expected: Option<usize>) -> HttpResponse<'_> {
+            HttpResponse::new(200, Trickle(body), expected)
+        }
+
+        #[test]
+        fn parses_json_as_it_arrives() {
+            let body = br#"{"readings": [1, 2, 3], "ok": true}"#;
+            let parsed: serde_json::Value = response(body, Some(body.len())).json().unwrap();
+
+            assert_eq!(parsed["readings"][2], 3);
+            assert_eq!(response(body, None).text().unwrap().len(), body.len());
+        }
+
+        #[test]
+        fn body_short_of_its_content_length_is_truncated() {
+            let body = br#"[1, 2, 3]"#;
+
+            assert_eq!(
+                response(body, Some(20)).json::<Vec<u8>>(),
+                Err(BodyError::Truncated(TruncatedBody {
+                    read: body.len(),
+                    expected: Some(20),
+                }))
+            );
+            assert!(matches!(
+                response(body, Some(20)).text(),
+                Err(BodyError::Truncated(_))
+            ));
+        }
+
+        #[test]
+        fn body_past_the_limit_is_truncated() {
+            let body = vec![b' '; MAX_BODY_LEN + 1];
+            let mut reader = BodyReader::new(body.as_slice(), None);
+            let error = BodyError::from(reader.read_to_end(&mut Vec::new()).unwrap_err());
+
+            let BodyError::Truncated(truncated) = error else {
+                panic!("{:?} isn't a truncation", error);
+            };
+            assert!(truncated.read <= MAX_BODY_LEN);
+            assert_eq!(truncated.expected, None);
+        }
+
+        #[test]
+        fn oversized_content_length_is_refused_up_front() {
+            let error = response(b"[]", Some(MAX_BODY_LEN + 1)).json::<Vec<u8>>();
+
+            assert_eq!(
+                error,
+                Err(BodyError::Truncated(TruncatedBody {
+                    read: 0,
+                    expected: Some(MAX_BODY_LEN + 1),
+                }))
+            );
+        }
+
+        #[test]
+        fn bad_json_is_malformed() {
+            let body = br#"{"readings": ["#;
+
+            assert!(matches!(
+                response(body, None).json::<serde_json::Value>(),
+                Err(BodyError::Malformed(_))
+            ));
+        }
+
+        #[test]
+        fn scripted_transport_replays_in_order() {
+            let mut transport = ScriptedTransport::new()
+                .respond(200, "1")
+                .fail("offline")
+                .respond_partial(200, "[1,", 10);
+
+            let first = transport
+                .post("http://a", &[("Accept", "text/plain")], "payload")
+                .unwrap();
+            assert_eq!(first.json::<u8>(), Ok(1));
+            assert!(transport.get("http://b", &[]).is_err());
+            let third = transport.get("http://c", &[]).unwrap();
+            assert!(matches!(third.text(), Err(BodyError::Truncated(_))));
+            assert!(transport.get("http://d", &[]).is_err());
+
+            assert_eq!(transport.remaining(), 0);
+            assert_eq!(
+                transport.requests()[0],
+                ("http://a".into(), "payload".into())
+            );
+            assert_eq!(transport.requests().len(), 4);
+            assert_eq!(transport.header(0, "accept"), Some("text/plain"));
+            assert_eq!(transport.header(1, "accept"), None);
+        }
+    }
+}
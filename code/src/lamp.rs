@@ -1,10 +1,11 @@
 pub mod lamp {
+    use crate::animation::animation::{Animation, AnimationPeriods, Crossfade, Effect};
+    use crate::color::color::{mix_oklab, to_led, LED_GAMMA};
     use crate::server::server::{ServableData, ServableDataReq, ServableDataRsp, ServerData};
     use crate::storage::storage::Storable;
-    use crate::sys::sys::uptime;
-    use esp_idf_hal::{gpio::OutputPin, peripheral::Peripheral, rmt::RmtChannel};
+    use crate::sys::sys::{uptime, uptime_ms};
     use log::info;
-    use rgb_led::{RGB8, WS2812RMT};
+    use rgb_led::{Chip, SmartLed, DEFAULT_LED_COUNT, MAX_LED_COUNT, RGB8};
     use serde::{Deserialize, Serialize};
     use std::sync::mpsc;
 
@@ -46,29 +47,58 @@ pub mod lamp {
         b: COLOR_MAX,
     };
 
+    pub const CYAN: RGB8 = RGB8 {
+        r: 0,
+        g: COLOR_MAX,
+        b: COLOR_MAX,
+    };
+
+    pub const ORANGE: RGB8 = RGB8 {
+        r: COLOR_MAX,
+        g: 96,
+        b: 0,
+    };
+
     #[allow(dead_code)]
+    #[derive(Debug, Copy, Clone, PartialEq)]
     pub enum LedState {
         Steady(RGB8),
         Breathe(RGB8),
+        // Washed out towards white, for readings that are getting old
+        Faded(RGB8),
+        Blink(RGB8),
+        Strobe(RGB8),
+        FadeIn(RGB8),
+        // Breathe with its own period, in ms
+        Pulse(RGB8, u32),
+        // Swing from the first color towards the second and back, period in ms
+        Shift(RGB8, RGB8, u32),
         Off,
     }
 
     impl LedState {
-        pub fn from_glucose(value: isize) -> LedState {
-            // Multi-colored colormap
-            // Red -> Green -> Blue -> Purple
-            match value {
-                0..55 => LedState::Breathe(RED),
-                55..152 => LedState::Steady(get_color_in_sweep(&RED, &GREEN, 152 - 55, value - 55)),
-                152..250 => {
-                    LedState::Steady(get_color_in_sweep(&GREEN, &BLUE, 250 - 152, value - 152))
+        pub fn animation(&self, started: u64) -> Animation {
+            let (effect, color) = match *self {
+                LedState::Pulse(color, period) => {
+                    return Animation::new(Effect::Breathe, color, started).with_period(period)
                 }
-                250..300 => {
-                    LedState::Steady(get_color_in_sweep(&BLUE, &PURPLE, 300 - 250, value - 250))
+                LedState::Shift(color, target, period) => {
+                    return Animation::new(Effect::Shift, color, started)
+                        .with_target(target)
+                        .with_period(period)
                 }
-                300..500 => LedState::Breathe(PURPLE),
-                _ => LedState::Breathe(WHITE),
-            }
+                LedState::Steady(color) => (Effect::Steady, color),
+                LedState::Breathe(color) => (Effect::Breathe, color),
+                LedState::Faded(color) => {
+                    (Effect::Steady, get_color_in_sweep(&color, &WHITE, 2, 1))
+                }
+                LedState::Blink(color) => (Effect::Blink, color),
+                LedState::Strobe(color) => (Effect::Strobe, color),
+                LedState::FadeIn(color) => (Effect::FadeIn, color),
+                LedState::Off => (Effect::Steady, BLACK),
+            };
+
+            Animation::new(effect, color, started)
         }
     }
 
@@ -78,22 +108,26 @@ pub mod lamp {
         total: usize,
         idx: isize,
     ) -> RGB8 {
-        let r_step = (end_color.r as f64 - start_color.r as f64) / (total as f64);
-        let g_step = (end_color.g as f64 - start_color.g as f64) / (total as f64);
-        let b_step = (end_color.b as f64 - start_color.b as f64) / (total as f64);
-
-        RGB8::new(
-            ((start_color.r as f64) + (idx as f64 * r_step)) as u8,
-            ((start_color.g as f64) + (idx as f64 * g_step)) as u8,
-            ((start_color.b as f64) + (idx as f64 * b_step)) as u8,
-        )
+        mix_oklab(start_color, end_color, idx as f32 / total as f32)
     }
 
-    pub struct Lamp<'a> {
-        state: LedState,
+    // Drives any kind of LED chain, so lamp output can be recorded off the hardware
+    pub struct Lamp<L: SmartLed> {
         brightness: f32,
         on: bool,
-        led: WS2812RMT<'a>,
+        led: L,
+        animation: Animation,
+        periods: AnimationPeriods,
+        crossfade: Option<Crossfade>,
+        // Last main color and brightness rendered, what crossfades start from
+        last_frame: Option<(RGB8, f32)>,
+        // Per-LED colors shown instead of the main color
+        overlay: Vec<Option<RGB8>>,
+        // Last colors and drive written to the strip, so unchanged frames can be skipped
+        last_pixels: Vec<RGB8>,
+        last_drive: f32,
+        led_count: usize,
+        chip: Chip,
         server_channel: Option<mpsc::Receiver<ServableDataReq>>,
         save_data: bool,
         last_changed: u64,
@@ -103,29 +137,40 @@ pub mod lamp {
     struct NvsLampState {
         brightness: f32,
         on: bool,
+        #[serde(default)]
+        periods: AnimationPeriods,
+        #[serde(default = "default_led_count")]
+        led_count: usize,
+        #[serde(default)]
+        chip: Chip,
+    }
+
+    fn default_led_count() -> usize {
+        DEFAULT_LED_COUNT
     }
 
+    // Brightness is perceptual, so this also gamma corrects for the LED
     pub fn set_bright(color: &RGB8, brightness: f32) -> RGB8 {
-        RGB8 {
-            r: ((color.r as f32) * brightness) as u8,
-            g: ((color.g as f32) * brightness) as u8,
-            b: ((color.b as f32) * brightness) as u8,
-        }
+        to_led(color, brightness)
     }
 
-    impl<'a> Lamp<'a> {
-        pub fn new(
-            led: impl Peripheral<P = impl OutputPin> + 'static,
-            channel: impl Peripheral<P = impl RmtChannel> + 'static,
-        ) -> Self {
-            let state = LedState::Off;
+    impl<L: SmartLed> Lamp<L> {
+        pub fn new(led: L) -> Self {
             let brightness = 0.25;
-            let led = WS2812RMT::new(led, channel).unwrap();
+            let led_count = led.count();
 
             Lamp {
-                state,
+                animation: LedState::Off.animation(0),
                 brightness,
                 led,
+                periods: AnimationPeriods::default(),
+                crossfade: None,
+                last_frame: None,
+                overlay: Vec::new(),
+                last_pixels: Vec::new(),
+                last_drive: 0.0,
+                led_count,
+                chip: Chip::default(),
                 on: true,
                 server_channel: None,
                 save_data: false,
@@ -134,11 +179,96 @@ pub mod lamp {
         }
 
         pub fn set_color(&mut self, color: LedState) {
-            self.state = color;
+            self.start_crossfade();
+            self.animation = color.animation(uptime_ms());
+            self.set_led();
+        }
+
+        // Advance any running animation, call this from the main loop. `now` is the
+        // uptime in ms.
+        pub fn tick(&mut self, now: u64) {
+            let fading = self.crossfade.is_some();
+            if self.last_frame.is_none() || fading || !self.animation.is_settled(now, &self.periods)
+            {
+                self.render(now);
+            }
+        }
+
+        // Whether a change is still blending in
+        pub fn is_fading(&self) -> bool {
+            self.crossfade.is_some()
+        }
+
+        // Blend from whatever is showing now into the next change
+        fn start_crossfade(&mut self) {
+            if let Some((from, from_level)) = self.last_frame {
+                self.crossfade = Some(Crossfade::new(
+                    from,
+                    from_level,
+                    uptime_ms(),
+                    self.periods.crossfade,
+                ));
+            }
+        }
+
+        pub fn set_periods(&mut self, periods: AnimationPeriods) {
+            self.periods = periods.clamped();
+            self.last_changed = uptime();
+            self.save_data = true;
+        }
+
+        pub fn led_count(&self) -> usize {
+            self.led_count
+        }
+
+        pub fn set_led_count(&mut self, count: usize) {
+            self.led_count = count.clamp(1, MAX_LED_COUNT);
+            self.led.set_count(self.led_count);
+            self.last_pixels.clear();
+            self.last_changed = uptime();
+            self.save_data = true;
+            self.set_led();
+        }
+
+        pub fn chip(&self) -> Chip {
+            self.chip
+        }
+
+        pub fn set_chip(&mut self, chip: Chip) {
+            if let Err(error) = self.led.set_chip(chip) {
+                info!("Couldn't switch LEDs to {:?}: {}", chip, error);
+                return;
+            }
+            self.chip = chip;
+            self.last_pixels.clear();
+            self.last_changed = uptime();
+            self.save_data = true;
+            self.set_led();
+        }
+
+        // Show `color` on LED `idx` instead of the main color, or go back to the
+        // main color with None. Follows the lamp's brightness and power.
+        pub fn set_pixel(&mut self, idx: usize, color: Option<RGB8>) {
+            if idx >= self.overlay.len() {
+                self.overlay.resize(idx + 1, None);
+            }
+            self.overlay[idx] = color;
+            self.set_led();
+        }
+
+        // Replace every per-LED color at once, from the start of the strip
+        pub fn set_pixels(&mut self, colors: &[Option<RGB8>]) {
+            self.overlay = colors.to_vec();
+            self.set_led();
+        }
+
+        pub fn clear_pixels(&mut self) {
+            self.overlay.clear();
             self.set_led();
         }
 
         pub fn set_brightness(&mut self, brightness: u8) {
+            self.start_crossfade();
             self.brightness = (brightness as f32) / 255.0;
             self.last_changed = uptime();
             self.save_data = true;
@@ -148,17 +278,8 @@ pub mod lamp {
         pub fn change_brightness(&mut self, brightness: i32) {
             // If the brightness is already max, don't change it
 
-            self.brightness += (brightness as f32) / 255.0;
-
-            // Don't let brightness be more than 1
-            if self.brightness > 1.0 {
-                self.brightness = 1.0;
-            }
-
-            // Don't let brightness be less than 0
-            if self.brightness < 0.0 {
-                self.brightness = 0.0;
-            }
+            self.start_crossfade();
+            self.brightness = (self.brightness + (brightness as f32) / 255.0).clamp(0.0, 1.0);
 
             self.last_changed = uptime();
             self.save_data = true;
@@ -166,6 +287,7 @@ pub mod lamp {
         }
 
         pub fn on(&mut self) {
+            self.start_crossfade();
             self.on = true;
             self.last_changed = uptime();
             self.save_data = true;
@@ -173,6 +295,7 @@ pub mod lamp {
         }
 
         pub fn off(&mut self) {
+            self.start_crossfade();
             self.on = false;
             self.last_changed = uptime();
             self.save_data = true;
@@ -180,6 +303,7 @@ pub mod lamp {
         }
 
         pub fn toggle(&mut self) {
+            self.start_crossfade();
             self.on = !self.on;
             self.last_changed = uptime();
             self.save_data = true;
@@ -187,38 +311,51 @@ pub mod lamp {
         }
 
         fn set_led(&mut self) {
-            match self.state {
-                LedState::Steady(color) => self
-                    .led
-                    .set_pixel(set_bright(
-                        &color,
-                        self.brightness * (self.on as i32 as f32),
-                    ))
-                    .unwrap(),
-                LedState::Breathe(color) => self
-                    .led
-                    .set_pixel(set_bright(
-                        &color,
-                        self.brightness * (self.on as i32 as f32),
-                    ))
-                    .unwrap(),
-                LedState::Off => self.led.set_pixel(BLACK).unwrap(),
-            };
+            self.render(uptime_ms());
+        }
+
+        fn render(&mut self, now: u64) {
+            let mut level = self.brightness * (self.on as i32 as f32);
+            let mut frame = self.animation.frame(now, &self.periods);
+
+            if let Some(crossfade) = &self.crossfade {
+                frame = crossfade.apply(now, &frame);
+                level = crossfade.level(now, level);
+                if crossfade.is_done(now) {
+                    self.crossfade = None;
+                }
+            }
+
+            self.last_frame = Some((frame, level));
+
+            // Colors go out at full drive, and the driver dims them all together
+            let pixels: Vec<RGB8> = (0..self.led_count)
+                .map(|idx| match self.overlay.get(idx) {
+                    Some(Some(color)) => set_bright(color, 1.0),
+                    _ => set_bright(&frame, 1.0),
+                })
+                .collect();
+            let drive = level.clamp(0.0, 1.0).powf(LED_GAMMA);
+
+            if self.last_pixels != pixels || self.last_drive != drive {
+                self.led.set_pixels_dimmed(&pixels, drive).unwrap();
+                self.last_pixels = pixels;
+                self.last_drive = drive;
+            }
         }
 
         fn get_nvs_state(&self) -> NvsLampState {
             NvsLampState {
                 brightness: self.brightness,
                 on: self.on,
+                periods: self.periods,
+                led_count: self.led_count,
+                chip: self.chip,
             }
         }
 
         pub fn need_to_save(&self) -> bool {
-            if self.save_data && (self.last_changed + SAVE_DELAY <= uptime()) {
-                return true;
-            }
-
-            false
+            self.save_data && (self.last_changed + SAVE_DELAY <= uptime())
         }
 
         pub fn saved(&mut self) {
@@ -226,9 +363,9 @@ pub mod lamp {
         }
     }
 
-    impl<'a> Storable for Lamp<'a> {
+    impl<L: SmartLed> Storable for Lamp<L> {
         fn store_tag(&self) -> &str {
-            return &"lamp_state";
+            "lamp_state"
         }
 
         fn store_data(&self) -> Vec<u8> {
@@ -241,10 +378,18 @@ pub mod lamp {
             let nvs_state = serde_json::from_slice::<NvsLampState>(data).unwrap();
             self.brightness = nvs_state.brightness;
             self.on = nvs_state.on;
+            self.periods = nvs_state.periods.clamped();
+            self.led_count = nvs_state.led_count.clamp(1, MAX_LED_COUNT);
+            self.led.set_count(self.led_count);
+            if let Err(error) = self.led.set_chip(nvs_state.chip) {
+                info!("Couldn't switch LEDs to {:?}: {}", nvs_state.chip, error);
+            } else {
+                self.chip = nvs_state.chip;
+            }
         }
     }
 
-    impl<'a> ServableData for Lamp<'a> {
+    impl<L: SmartLed> ServableData for Lamp<L> {
         fn get_channel(&mut self) -> mpsc::Sender<ServableDataReq> {
             let (tx, rx) = mpsc::channel::<ServableDataReq>();
             self.server_channel = Some(rx);
@@ -261,6 +406,9 @@ pub mod lamp {
                         let mut rsp = ServerData::new();
                         rsp.brightness = Some((self.brightness * 255.0) as i32 as u8);
                         rsp.on = Some(self.on);
+                        rsp.animation_periods = Some(self.periods);
+                        rsp.led_count = Some(self.led_count);
+                        rsp.led_chip = Some(self.chip);
                         back_channel.send(ServableDataRsp::Data(rsp)).unwrap();
                     }
 
@@ -270,6 +418,18 @@ pub mod lamp {
                             self.set_brightness(*brightness);
                         }
 
+                        if let Some(periods) = &update.animation_periods {
+                            self.set_periods(periods.merged_into(self.periods));
+                        }
+
+                        if let Some(count) = &update.led_count {
+                            self.set_led_count(*count);
+                        }
+
+                        if let Some(chip) = &update.led_chip {
+                            self.set_chip(*chip);
+                        }
+
                         // If we got a power state, then change our value
                         if let Some(on) = &update.on {
                             if *on {
@@ -287,6 +447,9 @@ pub mod lamp {
                     if let ServableDataReq::Reset = &req {
                         self.on();
                         self.brightness = 0.25f32;
+                        self.periods = AnimationPeriods::default();
+                        self.set_led_count(DEFAULT_LED_COUNT);
+                        self.set_chip(Chip::default());
                         self.last_changed = uptime();
                         self.save_data = true;
                     }
@@ -294,4 +457,195 @@ pub mod lamp {
             }
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::server::server::ServerUpdate;
+        use rgb_led::RecordingLed;
+
+        // Full brightness and no crossfades, so frames show the colors as set
+        fn lamp(count: usize) -> Lamp<RecordingLed> {
+            let mut lamp = Lamp::new(RecordingLed::new(count));
+            lamp.set_periods(AnimationPeriods {
+                crossfade: 0,
+                ..AnimationPeriods::default()
+            });
+            lamp.set_brightness(255);
+            lamp.led.clear();
+            lamp
+        }
+
+        #[test]
+        fn shows_the_color_on_every_led() {
+            let mut lamp = lamp(4);
+            lamp.set_color(LedState::Steady(RED));
+
+            assert_eq!(lamp.led.frames(), [vec![RED; 4]]);
+            assert_eq!(lamp.led_count(), 4);
+        }
+
+        #[test]
+        fn skips_unchanged_frames() {
+            let mut lamp = lamp(4);
+            lamp.set_color(LedState::Steady(RED));
+            lamp.set_color(LedState::Steady(RED));
+            lamp.tick(uptime_ms() + 10_000);
+
+            assert_eq!(lamp.led.frames().len(), 1);
+        }
+
+        #[test]
+        fn dims_with_the_brightness() {
+            let mut lamp = lamp(2);
+            lamp.set_color(LedState::Steady(WHITE));
+            lamp.set_brightness(128);
+
+            let dimmed = set_bright(&WHITE, 128.0 / 255.0);
+            assert_eq!(lamp.led.last_frame(), Some([dimmed; 2].as_slice()));
+        }
+
+        #[test]
+        fn off_goes_dark_and_on_comes_back() {
+            let mut lamp = lamp(3);
+            lamp.set_color(LedState::Steady(GREEN));
+            lamp.off();
+            assert_eq!(lamp.led.last_frame(), Some([BLACK; 3].as_slice()));
+
+            lamp.on();
+            assert_eq!(lamp.led.last_frame(), Some([GREEN; 3].as_slice()));
+        }
+
+        #[test]
+        fn overlay_pixels_replace_the_main_color() {
+            let mut lamp = lamp(4);
+            lamp.set_color(LedState::Steady(RED));
+            lamp.set_pixel(2, Some(BLUE));
+            assert_eq!(
+                lamp.led.last_frame(),
+                Some([RED, RED, BLUE, RED].as_slice())
+            );
+
+            lamp.set_pixels(&[None, Some(GREEN)]);
+            assert_eq!(
+                lamp.led.last_frame(),
+                Some([RED, GREEN, RED, RED].as_slice())
+            );
+
+            // Overlays follow the lamp's power too
+            lamp.off();
+            assert_eq!(lamp.led.last_frame(), Some([BLACK; 4].as_slice()));
+            lamp.on();
+
+            lamp.clear_pixels();
+            assert_eq!(lamp.led.last_frame(), Some([RED; 4].as_slice()));
+        }
+
+        #[test]
+        fn led_count_is_clamped_and_passed_on() {
+            let mut lamp = lamp(8);
+            lamp.set_color(LedState::Steady(RED));
+
+            lamp.set_led_count(3);
+            assert_eq!(lamp.led_count(), 3);
+            assert_eq!(lamp.led.last_frame(), Some([RED; 3].as_slice()));
+
+            lamp.set_led_count(0);
+            assert_eq!(lamp.led_count(), 1);
+            lamp.set_led_count(MAX_LED_COUNT + 1);
+            assert_eq!(lamp.led_count(), MAX_LED_COUNT);
+            assert_eq!(lamp.led.count(), MAX_LED_COUNT);
+        }
+
+        #[test]
+        fn chip_is_passed_on() {
+            let mut lamp = lamp(1);
+            lamp.set_chip(Chip::Sk6812Rgbw);
+
+            assert_eq!(lamp.chip(), Chip::Sk6812Rgbw);
+            assert_eq!(lamp.led.chip(), Chip::Sk6812Rgbw);
+        }
+
+        // Like the APA102 driver, which only speaks one protocol
+        struct OneChipLed(RecordingLed);
+
+        impl SmartLed for OneChipLed {
+            fn count(&self) -> usize {
+                self.0.count()
+            }
+
+            fn set_count(&mut self, count: usize) {
+                self.0.set_count(count)
+            }
+
+            fn set_pixels(&mut self, pixels: &[RGB8]) -> anyhow::Result<()> {
+                self.0.set_pixels(pixels)
+            }
+
+            fn set_chip(&mut self, chip: Chip) -> anyhow::Result<()> {
+                anyhow::ensure!(chip == Chip::default(), "can't drive {:?}", chip);
+                Ok(())
+            }
+        }
+
+        #[test]
+        fn refused_chips_are_not_reported() {
+            let mut lamp = Lamp::new(OneChipLed(RecordingLed::new(1)));
+            lamp.set_chip(Chip::Sk6812Rgbw);
+            assert_eq!(lamp.chip(), Chip::default());
+            assert!(!lamp.save_data);
+        }
+
+        #[test]
+        fn posted_periods_only_change_what_was_sent() {
+            let mut lamp = lamp(1);
+            let server = lamp.get_channel();
+            let update = |json: &str| {
+                ServableDataReq::Set(ServerUpdate {
+                    animation_periods: Some(serde_json::from_str(json).unwrap()),
+                    ..ServerUpdate::default()
+                })
+            };
+
+            server.send(update(r#"{"blink": 500}"#)).unwrap();
+            lamp.handle_server_req();
+            server.send(update(r#"{"breathe": 6000}"#)).unwrap();
+            lamp.handle_server_req();
+
+            assert_eq!(
+                lamp.periods,
+                AnimationPeriods {
+                    breathe: 6000,
+                    blink: 500,
+                    crossfade: 0,
+                    ..AnimationPeriods::default()
+                }
+            );
+        }
+
+        #[test]
+        fn tick_moves_animations_on() {
+            let mut lamp = lamp(1);
+            lamp.set_color(LedState::Blink(RED));
+            assert_eq!(lamp.led.last_frame(), Some([RED].as_slice()));
+
+            // Half way through the first blink
+            lamp.tick(uptime_ms() + 600);
+            assert_eq!(lamp.led.last_frame(), Some([BLACK].as_slice()));
+        }
+
+        #[test]
+        fn crossfades_start_from_what_was_showing() {
+            let mut lamp = lamp(1);
+            lamp.set_color(LedState::Steady(RED));
+            lamp.set_periods(AnimationPeriods::default());
+            lamp.set_color(LedState::Steady(BLUE));
+            assert!(lamp.is_fading());
+            assert_eq!(lamp.led.last_frame(), Some([RED].as_slice()));
+
+            lamp.tick(uptime_ms() + 60_000);
+            assert!(!lamp.is_fading());
+            assert_eq!(lamp.led.last_frame(), Some([BLUE].as_slice()));
+        }
+    }
 }
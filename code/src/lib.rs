@@ -1,8 +1,29 @@
+// Every module wraps its contents in a module of the same name
+#![allow(clippy::module_inception)]
+
+pub mod animation;
+pub mod color;
+pub mod colormap;
 pub mod dexcom;
+#[cfg(target_os = "espidf")]
 pub mod dimmer;
+pub mod glucose;
+pub mod graph;
+pub mod history;
+pub mod http;
 pub mod lamp;
+pub mod libre;
+pub mod nightscout;
+#[cfg(target_os = "espidf")]
 pub mod power;
+pub mod scheduler;
 pub mod server;
+pub mod share;
+pub mod stale;
+pub mod status;
 pub mod storage;
 pub mod sys;
+pub mod time;
+pub mod trend;
+#[cfg(target_os = "espidf")]
 pub mod wifi;
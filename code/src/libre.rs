@@ -0,0 +1,679 @@
+pub mod libre {
+    use crate::glucose::glucose::{GlucoseError, GlucoseReading, GlucoseSource, GlucoseTrend};
+    use crate::http::http::{HttpResponse, HttpTransport};
+    use crate::server::server::{ServableData, ServableDataReq, ServableDataRsp, ServerData};
+    use crate::storage::storage::Storable;
+    use crate::time::time::days_from_civil;
+    use log::info;
+    use serde::de::DeserializeOwned;
+    use serde::{Deserialize, Serialize};
+    use sha2::{Digest, Sha256};
+    use std::cmp::Reverse;
+    use std::sync::mpsc;
+
+    pub const GLOBAL_BASE_URL: &str = "https://api.libreview.io";
+
+    pub const LOGIN_ENDPOINT: &str = "llu/auth/login";
+    pub const CONNECTIONS_ENDPOINT: &str = "llu/connections";
+
+    // LibreLinkUp rejects clients that don't claim to be a recent app version
+    const PRODUCT: &str = "llu.android";
+    const VERSION: &str = "4.12.0";
+
+    // status field of the response envelope
+    const STATUS_OK: i32 = 0;
+    const STATUS_BAD_CREDENTIALS: i32 = 2;
+
+    #[derive(Serialize)]
+    struct LibreLogin<'a> {
+        email: &'a str,
+        password: &'a str,
+    }
+
+    #[derive(Deserialize, Debug)]
+    struct LibreEnvelope<D> {
+        status: i32,
+        data: Option<D>,
+        error: Option<LibreErrorMessage>,
+    }
+
+    #[derive(Deserialize, Debug)]
+    struct LibreErrorMessage {
+        message: Option<String>,
+    }
+
+    // A login either authenticates or tells us which regional server holds the account
+    #[derive(Deserialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    struct LibreLoginData {
+        redirect: Option<bool>,
+        region: Option<String>,
+        user: Option<LibreUser>,
+        auth_ticket: Option<LibreAuthTicket>,
+    }
+
+    #[derive(Deserialize, Debug)]
+    struct LibreUser {
+        id: String,
+    }
+
+    #[derive(Deserialize, Debug)]
+    struct LibreAuthTicket {
+        token: String,
+    }
+
+    #[derive(Deserialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    struct LibreConnection {
+        patient_id: String,
+        glucose_measurement: Option<LibreMeasurement>,
+    }
+
+    #[derive(Deserialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    struct LibreGraph {
+        connection: LibreConnection,
+        graph_data: Vec<LibreMeasurement>,
+    }
+
+    #[derive(Deserialize, Debug)]
+    #[serde(rename_all = "PascalCase")]
+    struct LibreMeasurement {
+        value_in_mg_per_dl: f32,
+        // Sensor time in UTC, e.g. "1/2/2024 3:04:05 PM"
+        factory_timestamp: String,
+        trend_arrow: Option<u8>,
+    }
+
+    impl LibreMeasurement {
+        fn to_reading(&self) -> Result<GlucoseReading, GlucoseError> {
+            Ok(GlucoseReading {
+                time: parse_timestamp(&self.factory_timestamp)?,
+                value: self.value_in_mg_per_dl.round() as isize,
+                trend: trend_from_arrow(self.trend_arrow),
+                system_offset: Some(0),
+                display_offset: None,
+            })
+        }
+    }
+
+    // Libre only has five arrows, graph points don't carry one at all
+    fn trend_from_arrow(arrow: Option<u8>) -> GlucoseTrend {
+        match arrow {
+            Some(1) => GlucoseTrend::SingleDown,
+            Some(2) => GlucoseTrend::FortyFiveDown,
+            Some(3) => GlucoseTrend::Flat,
+            Some(4) => GlucoseTrend::FortyFiveUp,
+            Some(5) => GlucoseTrend::SingleUp,
+            Some(_) => GlucoseTrend::NotComputable,
+            None => GlucoseTrend::NoTrend,
+        }
+    }
+
+    // "M/D/YYYY h:mm:ss AM" in UTC to milliseconds since the Unix epoch
+    fn parse_timestamp(timestamp: &str) -> Result<i64, GlucoseError> {
+        let bad = || GlucoseError::Unavailable(format!("bad timestamp {:?}", timestamp));
+
+        let mut parts = timestamp.split_whitespace();
+        let date = parts.next().ok_or_else(bad)?;
+        let time = parts.next().ok_or_else(bad)?;
+        let meridiem = parts.next().ok_or_else(bad)?;
+
+        let date: Vec<i64> = date
+            .split('/')
+            .map(|field| field.parse().map_err(|_| bad()))
+            .collect::<Result<_, _>>()?;
+        let time: Vec<i64> = time
+            .split(':')
+            .map(|field| field.parse().map_err(|_| bad()))
+            .collect::<Result<_, _>>()?;
+        let (&[month, day, year], &[hour, minute, second]) = (date.as_slice(), time.as_slice())
+        else {
+            return Err(bad());
+        };
+
+        let hour = match meridiem {
+            "AM" => hour % 12,
+            "PM" => hour % 12 + 12,
+            _ => return Err(bad()),
+        };
+
+        let seconds =
+            days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second;
+        Ok(seconds * 1000)
+    }
+
+    fn regional_url(region: &str) -> String {
+        format!("https://api-{}.libreview.io", region)
+    }
+
+    fn app_headers() -> [(&'static str, &'static str); 3] {
+        [
+            ("product", PRODUCT),
+            ("version", VERSION),
+            ("cache-control", "no-cache"),
+        ]
+    }
+
+    // Check both the HTTP status and the status inside the JSON envelope
+    fn unwrap_envelope<D: DeserializeOwned>(response: HttpResponse) -> Result<D, GlucoseError> {
+        match response.status {
+            200 => (),
+            401 => return Err(GlucoseError::SessionLost("token rejected".into())),
+            429 => return Err(GlucoseError::RateLimited),
+            status => {
+                return Err(GlucoseError::Unavailable(format!(
+                    "unexpected http status {}",
+                    status
+                )))
+            }
+        }
+
+        let envelope: LibreEnvelope<D> = response
+            .json()
+            .map_err(|e| GlucoseError::Unavailable(e.to_string()))?;
+
+        let message = envelope
+            .error
+            .and_then(|error| error.message)
+            .unwrap_or_default();
+
+        match envelope.status {
+            STATUS_OK => envelope
+                .data
+                .ok_or_else(|| GlucoseError::Unavailable("response without data".into())),
+            STATUS_BAD_CREDENTIALS => Err(GlucoseError::AuthFailed(message)),
+            status => Err(GlucoseError::Unavailable(format!(
+                "librelinkup status {}: {}",
+                status, message
+            ))),
+        }
+    }
+
+    pub struct Libre<T: HttpTransport> {
+        transport: T,
+        base_url: String,
+        token: Option<String>,
+        // SHA256 of the user ID, required alongside the token
+        account_id: Option<String>,
+        patient_id: Option<String>,
+        user_name: Option<String>,
+        user_pass: Option<String>,
+        region: Option<String>,
+        server_channel: Option<mpsc::Receiver<ServableDataReq>>,
+        save_data: bool,
+    }
+
+    impl<T: HttpTransport> Libre<T> {
+        pub fn new(transport: T) -> Self {
+            Libre {
+                transport,
+                base_url: GLOBAL_BASE_URL.to_string(),
+                token: None,
+                account_id: None,
+                patient_id: None,
+                user_name: None,
+                user_pass: None,
+                region: None,
+                server_channel: None,
+                save_data: false,
+            }
+        }
+
+        pub fn transport(&self) -> &T {
+            &self.transport
+        }
+
+        fn login(&mut self) -> Result<(), GlucoseError> {
+            let (Some(uname), Some(upass)) = (self.user_name.clone(), self.user_pass.clone())
+            else {
+                return Err(GlucoseError::NoCredentials);
+            };
+
+            self.token = None;
+            self.account_id = None;
+            self.base_url = match &self.region {
+                Some(region) => regional_url(region),
+                None => GLOBAL_BASE_URL.to_string(),
+            };
+
+            let login_ctx = serde_json::to_string(&LibreLogin {
+                email: &uname,
+                password: &upass,
+            })
+            .unwrap();
+
+            // The global server answers with a redirect for accounts that live elsewhere,
+            // which only ever needs following once
+            for _ in 0..2 {
+                let url = format!("{}/{}", self.base_url, LOGIN_ENDPOINT);
+                let response = self
+                    .transport
+                    .post(&url, &app_headers(), &login_ctx)
+                    .map_err(|e| GlucoseError::Unavailable(e.to_string()))?;
+                let login: LibreLoginData = unwrap_envelope(response)?;
+
+                if login.redirect.unwrap_or(false) {
+                    let region = login.region.ok_or_else(|| {
+                        GlucoseError::Unavailable("redirect without region".into())
+                    })?;
+                    info!("LibreLinkUp account lives in region {}", region);
+                    self.base_url = regional_url(&region);
+                    self.region = Some(region);
+                    self.save_data = true;
+                    continue;
+                }
+
+                let (Some(user), Some(ticket)) = (login.user, login.auth_ticket) else {
+                    return Err(GlucoseError::AuthFailed("no auth ticket in login".into()));
+                };
+
+                self.account_id = Some(format!("{:x}", Sha256::digest(user.id.as_bytes())));
+                self.token = Some(ticket.token);
+                return Ok(());
+            }
+
+            Err(GlucoseError::Unavailable("too many login redirects".into()))
+        }
+
+        fn get<D: DeserializeOwned>(&mut self, endpoint: &str) -> Result<D, GlucoseError> {
+            let (Some(token), Some(account_id)) = (&self.token, &self.account_id) else {
+                return Err(GlucoseError::SessionLost("not logged in".into()));
+            };
+
+            let url = format!("{}/{}", self.base_url, endpoint);
+            let authorization = format!("Bearer {}", token);
+            let mut headers: Vec<(&str, &str)> = app_headers().to_vec();
+            headers.push(("authorization", &authorization));
+            headers.push(("account-id", account_id.as_str()));
+
+            let response = self
+                .transport
+                .get(&url, &headers)
+                .map_err(|e| GlucoseError::Unavailable(e.to_string()))?;
+
+            unwrap_envelope(response)
+        }
+
+        // Authenticated GET that logs in again once if the token went stale
+        fn get_renewing<D: DeserializeOwned>(&mut self, endpoint: &str) -> Result<D, GlucoseError> {
+            match self.get(endpoint) {
+                Err(error) if error.is_session_failure() => {
+                    info!("LibreLinkUp session lost ({}), renewing", error);
+                    self.login()?;
+                    self.get(endpoint)
+                }
+                result => result,
+            }
+        }
+
+        // Follow the first sensor wearer shared with this account
+        fn get_patient_id(&mut self) -> Result<String, GlucoseError> {
+            if let Some(patient_id) = &self.patient_id {
+                return Ok(patient_id.clone());
+            }
+
+            let connections: Vec<LibreConnection> = self.get_renewing(CONNECTIONS_ENDPOINT)?;
+            let patient_id = connections
+                .into_iter()
+                .next()
+                .map(|connection| connection.patient_id)
+                .ok_or(GlucoseError::NotShared)?;
+
+            self.patient_id = Some(patient_id.clone());
+            Ok(patient_id)
+        }
+
+        fn get_graph(&mut self) -> Result<LibreGraph, GlucoseError> {
+            let patient_id = self.get_patient_id()?;
+            self.get_renewing(&format!("{}/{}/graph", CONNECTIONS_ENDPOINT, patient_id))
+        }
+
+        pub fn need_to_save(&self) -> bool {
+            self.save_data
+        }
+
+        pub fn saved(&mut self) {
+            self.save_data = false;
+        }
+    }
+
+    impl<T: HttpTransport> GlucoseSource for Libre<T> {
+        fn has_creds(&self) -> bool {
+            self.user_name.is_some() && self.user_pass.is_some()
+        }
+
+        fn reset_creds(&mut self) {
+            self.user_name = None;
+            self.user_pass = None;
+            self.region = None;
+            self.patient_id = None;
+            self.token = None;
+            self.save_data = true;
+        }
+
+        fn connect(&mut self) -> Result<(), GlucoseError> {
+            self.patient_id = None;
+            self.login()?;
+            self.get_patient_id().map(|_| ())
+        }
+
+        fn get_latest_glucose(&mut self) -> Result<GlucoseReading, GlucoseError> {
+            self.get_graph()?
+                .connection
+                .glucose_measurement
+                .ok_or(GlucoseError::NoReadings)?
+                .to_reading()
+        }
+
+        // The graph covers the last 12 hours, which is as far back as LibreLinkUp goes
+        fn get_glucose_history(&mut self) -> Result<Vec<GlucoseReading>, GlucoseError> {
+            let graph = self.get_graph()?;
+
+            let mut readings = graph
+                .graph_data
+                .iter()
+                .map(|measurement| measurement.to_reading())
+                .collect::<Result<Vec<_>, _>>()?;
+            if let Some(latest) = &graph.connection.glucose_measurement {
+                readings.push(latest.to_reading()?);
+            }
+
+            readings.sort_by_key(|reading| Reverse(reading.time));
+            readings.dedup_by_key(|reading| reading.time);
+            Ok(readings)
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct NvsLibreState {
+        user_name: Option<String>,
+        user_pass: Option<String>,
+        #[serde(default)]
+        region: Option<String>,
+    }
+
+    impl<T: HttpTransport> Storable for Libre<T> {
+        fn store_tag(&self) -> &str {
+            "libre_creds"
+        }
+
+        fn store_data(&self) -> Vec<u8> {
+            let data = NvsLibreState {
+                user_name: self.user_name.to_owned(),
+                user_pass: self.user_pass.to_owned(),
+                region: self.region.to_owned(),
+            };
+
+            serde_json::to_string(&data).unwrap().into_bytes()
+        }
+
+        fn recall_data(&mut self, data: &[u8]) {
+            let nvs_state = serde_json::from_slice::<NvsLibreState>(data).unwrap();
+            self.user_name = nvs_state.user_name;
+            self.user_pass = nvs_state.user_pass;
+            self.region = nvs_state.region;
+            self.save_data = false;
+        }
+    }
+
+    impl<T: HttpTransport> ServableData for Libre<T> {
+        fn get_channel(&mut self) -> mpsc::Sender<ServableDataReq> {
+            let (tx, rx) = mpsc::channel::<ServableDataReq>();
+            self.server_channel = Some(rx);
+            tx
+        }
+
+        fn handle_server_req(&mut self) {
+            if let Some(channel) = &self.server_channel {
+                if let Ok(req) = channel.try_recv() {
+                    info!("libre got a request from server");
+
+                    if let ServableDataReq::Get(back_channel) = &req {
+                        info!("Sending libre state to server");
+                        let mut rsp = ServerData::new();
+                        rsp.libre_user_stored = Some(self.user_name.is_some());
+                        rsp.libre_pass_stored = Some(self.user_pass.is_some());
+                        back_channel.send(ServableDataRsp::Data(rsp)).unwrap();
+                    }
+
+                    if let ServableDataReq::Set(update) = &req {
+                        if let Some(libre_uname) = &update.libre_user {
+                            self.user_name = Some(libre_uname.clone());
+                            // A new account may live in a different region
+                            self.region = None;
+                            self.patient_id = None;
+                            self.save_data = true;
+                        }
+
+                        if let Some(libre_pass) = &update.libre_pass {
+                            self.user_pass = Some(libre_pass.clone());
+                            self.save_data = true;
+                        }
+                    }
+
+                    if let ServableDataReq::Reset = &req {
+                        self.reset_creds();
+                    }
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::http::http::ScriptedTransport;
+
+        const LOGIN: &str =
+            r#"{"status":0,"data":{"user":{"id":"user-1"},"authTicket":{"token":"token-1"}}}"#;
+
+        const CONNECTIONS: &str = r#"{"status":0,"data":[{"patientId":"patient-1"}]}"#;
+        const GRAPH: &str = r#"{"status":0,"data":{
+            "connection":{"patientId":"patient-1","glucoseMeasurement":
+                {"ValueInMgPerDl":123.4,"FactoryTimestamp":"1/2/2024 3:04:05 PM","TrendArrow":4}},
+            "graphData":[
+                {"ValueInMgPerDl":110,"FactoryTimestamp":"1/2/2024 2:59:05 PM"},
+                {"ValueInMgPerDl":100,"FactoryTimestamp":"1/2/2024 2:54:05 PM"}
+            ]}}"#;
+
+        fn libre(transport: ScriptedTransport) -> Libre<ScriptedTransport> {
+            let mut libre = Libre::new(transport);
+            libre.user_name = Some("user@example.com".to_string());
+            libre.user_pass = Some("pass".to_string());
+            libre
+        }
+
+        fn urls(libre: &Libre<ScriptedTransport>) -> Vec<&str> {
+            libre
+                .transport
+                .requests()
+                .iter()
+                .map(|(url, _)| url.as_str())
+                .collect()
+        }
+
+        #[test]
+        fn parses_timestamps() {
+            assert_eq!(parse_timestamp("1/2/2024 3:04:05 PM"), Ok(1704207845000));
+            assert_eq!(parse_timestamp("2/29/2024 11:59:59 PM"), Ok(1709251199000));
+            // Twelve o'clock belongs to the half of the day it starts
+            assert_eq!(parse_timestamp("1/1/2024 12:00:00 AM"), Ok(1704067200000));
+            assert_eq!(parse_timestamp("1/1/2024 12:30:00 PM"), Ok(1704112200000));
+        }
+
+        #[test]
+        fn refuses_bad_timestamps() {
+            for timestamp in [
+                "",
+                "1/2/2024 3:04:05",
+                "1/2/2024 3:04:05 XM",
+                "1/2 3:04:05 PM",
+                "1/2/2024 3:04 PM",
+                "one/2/2024 3:04:05 PM",
+            ] {
+                assert!(parse_timestamp(timestamp).is_err(), "{:?}", timestamp);
+            }
+        }
+
+        #[test]
+        fn maps_trend_arrows() {
+            let trends = (0..=6)
+                .map(|arrow| trend_from_arrow(Some(arrow)))
+                .chain([trend_from_arrow(None)]);
+            let expected = [
+                GlucoseTrend::NotComputable,
+                GlucoseTrend::SingleDown,
+                GlucoseTrend::FortyFiveDown,
+                GlucoseTrend::Flat,
+                GlucoseTrend::FortyFiveUp,
+                GlucoseTrend::SingleUp,
+                GlucoseTrend::NotComputable,
+                GlucoseTrend::NoTrend,
+            ];
+
+            assert!(trends.eq(expected));
+        }
+
+        #[test]
+        fn login_then_connections_then_graph() {
+            let transport = ScriptedTransport::new()
+                .respond(200, LOGIN)
+                .respond(200, CONNECTIONS)
+                .respond(200, GRAPH)
+                .respond(200, GRAPH);
+            let mut libre = libre(transport);
+
+            libre.connect().unwrap();
+            let latest = libre.get_latest_glucose().unwrap();
+            assert_eq!(latest.value, 123);
+            assert_eq!(latest.time, 1704207845000);
+            assert!(matches!(latest.trend, GlucoseTrend::FortyFiveUp));
+
+            let history = libre.get_glucose_history().unwrap();
+            let times: Vec<i64> = history.iter().map(|reading| reading.time).collect();
+            assert_eq!(times, [1704207845000, 1704207545000, 1704207245000]);
+
+            assert_eq!(
+                urls(&libre),
+                [
+                    "https://api.libreview.io/llu/auth/login",
+                    "https://api.libreview.io/llu/connections",
+                    "https://api.libreview.io/llu/connections/patient-1/graph",
+                    "https://api.libreview.io/llu/connections/patient-1/graph",
+                ]
+            );
+            let account_id = format!("{:x}", Sha256::digest(b"user-1"));
+            assert_eq!(
+                libre.transport.header(1, "account-id"),
+                Some(account_id.as_str())
+            );
+            assert_eq!(
+                libre.transport.header(1, "authorization"),
+                Some("Bearer token-1")
+            );
+            assert_eq!(libre.transport.header(0, "product"), Some(PRODUCT));
+        }
+
+        #[test]
+        fn follows_the_region_redirect() {
+            let transport = ScriptedTransport::new()
+                .respond(
+                    200,
+                    r#"{"status":0,"data":{"redirect":true,"region":"eu"}}"#,
+                )
+                .respond(200, LOGIN)
+                .respond(200, CONNECTIONS);
+            let mut libre = libre(transport);
+
+            libre.connect().unwrap();
+            assert_eq!(
+                urls(&libre),
+                [
+                    "https://api.libreview.io/llu/auth/login",
+                    "https://api-eu.libreview.io/llu/auth/login",
+                    "https://api-eu.libreview.io/llu/connections",
+                ]
+            );
+            assert_eq!(libre.region.as_deref(), Some("eu"));
+            assert!(libre.need_to_save());
+        }
+
+        #[test]
+        fn gives_up_on_endless_redirects() {
+            let redirect = r#"{"status":0,"data":{"redirect":true,"region":"eu"}}"#;
+            let transport = ScriptedTransport::new()
+                .respond(200, redirect)
+                .respond(200, redirect);
+            let mut libre = libre(transport);
+
+            assert!(matches!(
+                libre.connect().unwrap_err(),
+                GlucoseError::Unavailable(_)
+            ));
+            assert_eq!(libre.transport.remaining(), 0);
+        }
+
+        #[test]
+        fn logs_in_again_when_the_token_is_refused() {
+            let transport = ScriptedTransport::new()
+                .respond(200, LOGIN)
+                .respond(200, CONNECTIONS)
+                .respond(401, "")
+                .respond(200, LOGIN)
+                .respond(200, GRAPH);
+            let mut libre = libre(transport);
+
+            libre.connect().unwrap();
+            assert_eq!(libre.get_latest_glucose().unwrap().value, 123);
+            assert_eq!(
+                urls(&libre)[2..],
+                [
+                    "https://api.libreview.io/llu/connections/patient-1/graph",
+                    "https://api.libreview.io/llu/auth/login",
+                    "https://api.libreview.io/llu/connections/patient-1/graph",
+                ]
+            );
+        }
+
+        #[test]
+        fn refused_twice_is_a_lost_session() {
+            let transport = ScriptedTransport::new()
+                .respond(200, LOGIN)
+                .respond(200, CONNECTIONS)
+                .respond(401, "")
+                .respond(200, LOGIN)
+                .respond(401, "");
+            let mut libre = libre(transport);
+
+            libre.connect().unwrap();
+            assert!(libre.get_latest_glucose().unwrap_err().is_session_failure());
+            assert_eq!(libre.transport.remaining(), 0);
+        }
+
+        #[test]
+        fn bad_credentials_fail_the_login() {
+            let body = r#"{"status":2,"error":{"message":"notAuthenticated"}}"#;
+            let mut libre = libre(ScriptedTransport::new().respond(200, body));
+
+            assert_eq!(
+                libre.connect().unwrap_err(),
+                GlucoseError::AuthFailed("notAuthenticated".into())
+            );
+        }
+
+        #[test]
+        fn nothing_shared_is_not_an_auth_failure() {
+            let transport = ScriptedTransport::new()
+                .respond(200, LOGIN)
+                .respond(200, r#"{"status":0,"data":[]}"#);
+            let mut libre = libre(transport);
+
+            let error = libre.connect().unwrap_err();
+
+            assert_eq!(error, GlucoseError::NotShared);
+            assert!(!error.is_auth_failure());
+            assert!(libre.has_creds());
+        }
+    }
+}
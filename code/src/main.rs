@@ -6,16 +6,34 @@ use esp_idf_svc::{eventloop::EspSystemEventLoop, nvs::EspDefaultNvsPartition};
 
 use esp_idf_hal::gpio::PinDriver;
 
+use cgmlamp::colormap::colormap::ColorMap;
 use cgmlamp::dexcom::dexcom::Dexcom;
 use cgmlamp::dimmer::dimmer::LightDimmer;
+use cgmlamp::glucose::glucose::{
+    GlucoseError, GlucoseSource, SourceKind, SourceSelect, UnitSelect,
+};
+use cgmlamp::graph::graph::HistoryGraph;
+use cgmlamp::history::history::GlucoseHistory;
+use cgmlamp::http::http::EspTransport;
 use cgmlamp::lamp::lamp::Lamp;
-use cgmlamp::lamp::lamp::{LedState, WHITE};
+use cgmlamp::lamp::lamp::LedState;
+use cgmlamp::libre::libre::Libre;
+use cgmlamp::nightscout::nightscout::Nightscout;
 use cgmlamp::power::power::Power;
+use cgmlamp::scheduler::scheduler::PollScheduler;
 use cgmlamp::server::server::ServableData;
 use cgmlamp::server::server::Server;
+use cgmlamp::stale::stale::{ReadingStage, StaleMonitor};
+use cgmlamp::status::status::{LampStatus, StatusIndicator};
 use cgmlamp::storage::storage::Storage;
-use cgmlamp::sys::sys::{uptime, Sys};
+use cgmlamp::sys::sys::{uptime, uptime_ms, Sys};
+use cgmlamp::time::time::{wall_clock_ms, TimeService};
+use cgmlamp::trend::trend::TrendMap;
 use cgmlamp::wifi::wifi::Wifi;
+#[cfg(feature = "apa102")]
+use rgb_led::Apa102;
+#[cfg(not(feature = "apa102"))]
+use rgb_led::WS2812RMT;
 
 // Application state machine states
 enum AppState {
@@ -60,13 +78,67 @@ fn main() -> anyhow::Result<()> {
     let bat_charge_pin = PinDriver::input(peripherals.pins.gpio4)?;
     let mut power = Power::new(i2c, sda, scl, bat_charge_pin).unwrap();
 
-    // Create dexcom object
-    let mut dexcom = Dexcom::new();
+    // Create glucose sources, and the setting that picks between them
+    let mut dexcom = Dexcom::new(EspTransport::new()?);
     storage.recall(&mut dexcom).unwrap_or_else(|error| {
         info!("Couldn't load dexcom settings from flash: {}", error);
     });
 
-    let mut lamp = Lamp::new(peripherals.pins.gpio8, peripherals.rmt.channel0);
+    let mut nightscout = Nightscout::new(EspTransport::new()?);
+    storage.recall(&mut nightscout).unwrap_or_else(|error| {
+        info!("Couldn't load nightscout settings from flash: {}", error);
+    });
+
+    let mut libre = Libre::new(EspTransport::new()?);
+    storage.recall(&mut libre).unwrap_or_else(|error| {
+        info!("Couldn't load libre settings from flash: {}", error);
+    });
+
+    let mut source_select = SourceSelect::new();
+    storage.recall(&mut source_select).unwrap_or_else(|error| {
+        info!("Couldn't load glucose source from flash: {}", error);
+    });
+
+    let mut time = TimeService::new();
+    storage.recall(&mut time).unwrap_or_else(|error| {
+        info!("Couldn't load time settings from flash: {}", error);
+    });
+
+    let mut stale = StaleMonitor::new();
+    storage.recall(&mut stale).unwrap_or_else(|error| {
+        info!("Couldn't load stale thresholds from flash: {}", error);
+    });
+
+    let mut unit_select = UnitSelect::new();
+    storage.recall(&mut unit_select).unwrap_or_else(|error| {
+        info!("Couldn't load glucose unit from flash: {}", error);
+    });
+
+    let mut color_map = ColorMap::new();
+    storage.recall(&mut color_map).unwrap_or_else(|error| {
+        info!("Couldn't load color map from flash: {}", error);
+    });
+
+    let mut trend_map = TrendMap::new();
+    storage.recall(&mut trend_map).unwrap_or_else(|error| {
+        info!("Couldn't load trend styles from flash: {}", error);
+    });
+
+    let mut graph = HistoryGraph::new();
+    storage.recall(&mut graph).unwrap_or_else(|error| {
+        info!("Couldn't load history graph settings from flash: {}", error);
+    });
+
+    #[cfg(not(feature = "apa102"))]
+    let led = WS2812RMT::new(peripherals.pins.gpio8, peripherals.rmt.channel0)?;
+    // Data stays on the LED pin, the clock goes on the extension terminal
+    #[cfg(feature = "apa102")]
+    let led = Apa102::new(
+        peripherals.spi2,
+        peripherals.pins.gpio10,
+        peripherals.pins.gpio8,
+    )?;
+    let mut lamp = Lamp::new(led);
     storage.recall(&mut lamp).unwrap_or_else(|error| {
         info!("Couldn't load lamp settings from flash: {}", error);
     });
@@ -81,12 +153,44 @@ fn main() -> anyhow::Result<()> {
     server.add_data_channel(&mut lamp);
     server.add_data_channel(&mut wifi);
     server.add_data_channel(&mut dexcom);
+    server.add_data_channel(&mut nightscout);
+    server.add_data_channel(&mut libre);
+    server.add_data_channel(&mut source_select);
     server.add_data_channel(&mut power);
     server.add_data_channel(&mut sys);
 
-    let mut no_measurement_count = 0;
-    let mut last_query: u64 = 0;
-    const QUERY_INTERVAL: u64 = 20;
+    let mut scheduler = PollScheduler::new();
+    server.add_data_channel(&mut scheduler);
+
+    let mut history = GlucoseHistory::new();
+    server.add_data_channel(&mut history);
+    server.add_data_channel(&mut stale);
+    server.add_data_channel(&mut time);
+    server.add_unit_channel(&mut unit_select);
+    server.add_data_channel(&mut color_map);
+    server.add_data_channel(&mut trend_map);
+    server.add_data_channel(&mut graph);
+
+    let mut indicator = StatusIndicator::new();
+    server.add_data_channel(&mut indicator);
+
+    // What the lamp is currently showing for the latest reading's age
+    let mut shown_led_state: Option<LedState> = None;
+
+    let mut last_connect: u64 = 0;
+    let mut connect_interval: u64 = 0;
+    const CONNECT_RETRY_INTERVAL: u64 = 60;
+    const RATE_LIMIT_INTERVAL: u64 = 600;
+    // Sources lock accounts after too many bad logins, so don't hammer them
+    const AUTH_RETRY_INTERVAL: u64 = 600;
+
+    // Which source the current session belongs to
+    let mut session_source: Option<SourceKind> = None;
+
+    // Uptime the boot signature went up, in ms. Every state after boot replaces
+    // it, so it's held long enough to be seen.
+    let mut booting_since: Option<u64> = None;
+    const BOOT_SIGNATURE_MS: u64 = 2000;
 
     // Set up encoder
     let mut pin_a = peripherals.pins.gpio18;
@@ -97,9 +201,7 @@ fn main() -> anyhow::Result<()> {
     let mut last_button_state = true;
 
     loop {
-        // Get time now. Adding the interval will make the first measurement
-        // happen immediately.
-        let now = uptime() + QUERY_INTERVAL;
+        let now = uptime();
 
         // Check for encoder change and update brightness
         let bright_change = dimmer.get_change();
@@ -122,8 +224,21 @@ fn main() -> anyhow::Result<()> {
         lamp.handle_server_req();
         wifi.handle_server_req();
         dexcom.handle_server_req();
+        nightscout.handle_server_req();
+        libre.handle_server_req();
+        source_select.handle_server_req();
         power.handle_server_req();
         sys.handle_server_req();
+        scheduler.handle_server_req();
+        history.handle_server_req();
+        stale.handle_server_req();
+        time.handle_server_req();
+        unit_select.handle_server_req();
+        color_map.handle_server_req();
+        trend_map.handle_server_req();
+        graph.handle_server_req();
+        indicator.handle_server_req();
+        time.update();
 
         // Let each object that needs to store data do so
         if wifi.need_to_save() {
@@ -136,6 +251,62 @@ fn main() -> anyhow::Result<()> {
             dexcom.saved();
         }
 
+        if nightscout.need_to_save() {
+            storage.store(&mut nightscout).unwrap();
+            nightscout.saved();
+        }
+
+        if libre.need_to_save() {
+            storage.store(&mut libre).unwrap();
+            libre.saved();
+        }
+
+        if source_select.need_to_save() {
+            storage.store(&mut source_select).unwrap();
+            source_select.saved();
+        }
+
+        let source_kind = source_select.kind();
+        let source: &mut dyn GlucoseSource = match source_kind {
+            SourceKind::Dexcom => &mut dexcom,
+            SourceKind::Nightscout => &mut nightscout,
+            SourceKind::Libre => &mut libre,
+        };
+
+        if graph.need_to_save() {
+            storage.store(&mut graph).unwrap();
+            graph.saved();
+        }
+
+        if trend_map.need_to_save() {
+            storage.store(&mut trend_map).unwrap();
+            trend_map.saved();
+        }
+
+        // The history graph is drawn with the color map, so redraw it on any change
+        if color_map.need_to_save() {
+            storage.store(&mut color_map).unwrap();
+            color_map.saved();
+            graph.invalidate();
+        }
+
+        if unit_select.need_to_save() {
+            storage.store(&mut unit_select).unwrap();
+            unit_select.saved();
+            graph.invalidate();
+        }
+        history.set_unit(unit_select.unit());
+
+        if time.need_to_save() {
+            storage.store(&mut time).unwrap();
+            time.saved();
+        }
+
+        if stale.need_to_save() {
+            storage.store(&mut stale).unwrap();
+            stale.saved();
+        }
+
         if lamp.need_to_save() {
             storage.store(&mut lamp).unwrap();
             lamp.saved();
@@ -144,30 +315,38 @@ fn main() -> anyhow::Result<()> {
         match app_state {
             AppState::Boot => {
                 // Update presentation
-                lamp.set_color(LedState::Steady(WHITE));
+                indicator.show(LampStatus::Booting, &mut lamp);
+                let shown_at = *booting_since.get_or_insert_with(uptime_ms);
 
-                if wifi.has_creds() && dexcom.has_creds() {
+                if uptime_ms() < shown_at + BOOT_SIGNATURE_MS {
+                    // Leave the boot signature up
+                } else if wifi.has_creds() && source.has_creds() {
+                    indicator.show(LampStatus::WifiConnecting, &mut lamp);
                     app_state = AppState::ConnectWifi;
                 } else {
                     // Advance to next state
+                    indicator.show(LampStatus::ApMode, &mut lamp);
                     app_state = AppState::PresentAp;
                 }
             }
             AppState::PresentAp => {
+                // The status says why we're in AP mode, so leave it be
                 wifi.start_ap().unwrap();
 
                 server.start().unwrap();
 
-                lamp.set_color(LedState::Breathe(WHITE));
                 app_state = AppState::WaitForConfig;
             }
             AppState::WaitForConfig => {
-                if wifi.has_creds() && dexcom.has_creds() {
+                if wifi.has_creds() && source.has_creds() {
                     server.stop();
-                    lamp.set_color(LedState::Steady(WHITE));
+                    indicator.show(LampStatus::WifiConnecting, &mut lamp);
                     app_state = AppState::ConnectWifi;
                 }
             }
+            // Connecting blocks the loop, so let the lamp finish fading to the new
+            // status first
+            AppState::ConnectWifi | AppState::GetSession if lamp.is_fading() => {}
             AppState::ConnectWifi => {
                 // Set up wifi, connect to AP
                 match wifi.start_sta() {
@@ -175,28 +354,111 @@ fn main() -> anyhow::Result<()> {
                         // Start the http server
                         info!("Wifi connected, starting web interface");
                         server.start().unwrap();
+
+                        if !time.is_running() {
+                            time.start().unwrap_or_else(|error| {
+                                info!("Couldn't start SNTP: {}", error);
+                            });
+                        }
+
+                        indicator.show(LampStatus::SourceConnecting, &mut lamp);
                         app_state = AppState::GetSession;
                     }
                     Err(_) => {
                         // If connection fails too many times, open in AP mode
                         info!("Couldn't connect to wifi, launching AP mode for AP credentials");
                         wifi.reset_creds();
+                        indicator.show(LampStatus::WifiFailed, &mut lamp);
                         app_state = AppState::PresentAp;
                     }
                 }
             }
             AppState::GetSession => {
-                dexcom.connect().unwrap();
-
-                // TODO: Check for valid dexcom credentials
-
-                app_state = AppState::DisplayGlucose;
+                if now > (last_connect + connect_interval) {
+                    last_connect = now;
+
+                    match source.connect() {
+                        Ok(_) => {
+                            connect_interval = 0;
+                            scheduler.reset();
+
+                            // Switching sources means the old readings may not line up
+                            if session_source != Some(source_kind) {
+                                history.clear();
+                                stale.clear();
+                            }
+                            session_source = Some(source_kind);
+                            shown_led_state = None;
+
+                            // Fill in whatever we missed while disconnected
+                            match source.get_glucose_history() {
+                                Ok(readings) => {
+                                    let added = history.extend(readings);
+                                    info!("Backfilled {} readings", added);
+                                }
+                                Err(error) => info!("Couldn't backfill history: {}", error),
+                            }
+                            app_state = AppState::DisplayGlucose;
+                        }
+                        Err(GlucoseError::NoCredentials) => {
+                            info!("No {:?} credentials, launching AP mode", source_kind);
+                            server.stop();
+                            indicator.show(LampStatus::ApMode, &mut lamp);
+                            app_state = AppState::PresentAp;
+                        }
+                        Err(error) if error.is_auth_failure() => {
+                            // Could be a bad password, or the source having a bad day.
+                            // Keep the credentials, new ones can be set from the web
+                            // interface in the meantime.
+                            info!("{:?} login refused ({}), backing off", source_kind, error);
+                            indicator.show(LampStatus::AuthFailed, &mut lamp);
+                            connect_interval = AUTH_RETRY_INTERVAL;
+                        }
+                        Err(GlucoseError::NotShared) => {
+                            info!("Nothing shared with this {:?} account yet", source_kind);
+                            indicator.show(LampStatus::NoReadings, &mut lamp);
+                            connect_interval = CONNECT_RETRY_INTERVAL;
+                        }
+                        Err(GlucoseError::RateLimited) => {
+                            info!("{:?} login rate limited, backing off", source_kind);
+                            connect_interval = RATE_LIMIT_INTERVAL;
+                        }
+                        Err(error) => {
+                            info!(
+                                "Couldn't connect to {:?} ({}), retrying",
+                                source_kind, error
+                            );
+                            connect_interval = CONNECT_RETRY_INTERVAL;
+                        }
+                    }
+                }
             }
             AppState::DisplayGlucose => {
-                if !wifi.has_creds() || !dexcom.has_creds() {
+                let status = match stale.stage(now, wall_clock_ms()) {
+                    None => LampStatus::NoReadings,
+                    Some(ReadingStage::Fresh | ReadingStage::Aging) => LampStatus::Ok,
+                    Some(ReadingStage::Stale | ReadingStage::Lost) => LampStatus::Stale,
+                };
+                indicator.show(status, &mut lamp);
+
+                // Keep the lamp in step with the latest reading, and let it show
+                // when that reading is getting old
+                let led_state = stale.led_state(now, wall_clock_ms(), &color_map, &trend_map);
+                if led_state.is_some() && led_state != shown_led_state {
+                    info!("Lamp now showing {:?}", led_state);
+                    lamp.set_color(led_state.unwrap());
+                    shown_led_state = led_state;
+                }
+
+                if !wifi.has_creds() || !source.has_creds() {
                     server.stop();
+                    indicator.show(LampStatus::ApMode, &mut lamp);
                     app_state = AppState::PresentAp;
-                } else if now > (last_query + QUERY_INTERVAL) {
+                } else if session_source != Some(source_kind) {
+                    info!("Glucose source changed to {:?}, connecting", source_kind);
+                    indicator.show(LampStatus::SourceConnecting, &mut lamp);
+                    app_state = AppState::GetSession;
+                } else if scheduler.due(now) {
                     let soc = power.batt_charge().unwrap();
                     let voltage = power.batt_voltage().unwrap();
                     let charge_rate = power.batt_charge_rate().unwrap();
@@ -205,33 +467,60 @@ fn main() -> anyhow::Result<()> {
                     info!("Voltage: {:.2}V", voltage);
                     info!("Battery charging: {}", power.batt_charging());
 
-                    // Update last
                     info!("{}: getting latest glucose", now);
-                    last_query = now;
-                    no_measurement_count += 1;
 
                     // Are we still connected to wifi? If not, sending a request will crash the program
                     if !wifi.is_connected() {
                         // TODO: Not enough to prevent a crash when radio -> init
                         info!("Not connected to wifi, reconnecting");
                         server.stop();
+                        indicator.show(LampStatus::WifiConnecting, &mut lamp);
                         app_state = AppState::ConnectWifi;
                     } else {
                         // Get new reading
-                        if let Ok(measurement) = dexcom.get_latest_glucose() {
-                            info!("{:?}", measurement);
-
-                            lamp.set_color(LedState::from_glucose(measurement.value));
-                            no_measurement_count = 0;
-                        } else if no_measurement_count >= 600 {
-                            lamp.set_color(LedState::Steady(WHITE));
+                        match source.get_latest_glucose() {
+                            Ok(measurement) => {
+                                info!("{:?}", measurement);
+                                scheduler.reading_received(now, wall_clock_ms(), &measurement);
+                                history.insert(measurement);
+                                stale.reading_received(now, wall_clock_ms(), &measurement);
+                            }
+                            Err(GlucoseError::NoReadings) => {
+                                scheduler.no_new_reading(now);
+                            }
+                            Err(error) if error.is_session_failure() => {
+                                info!("Session lost ({}), logging in again", error);
+                                indicator.show(LampStatus::SourceConnecting, &mut lamp);
+                                app_state = AppState::GetSession;
+                            }
+                            Err(error) if error.is_auth_failure() => {
+                                info!("Credentials rejected ({}), backing off", error);
+                                indicator.show(LampStatus::AuthFailed, &mut lamp);
+                                last_connect = now;
+                                connect_interval = AUTH_RETRY_INTERVAL;
+                                app_state = AppState::GetSession;
+                            }
+                            Err(error) => {
+                                info!("Couldn't get glucose: {}", error);
+                                scheduler.failed(now);
+                            }
                         }
                     }
                 }
             }
         };
 
-        // 100 ms delay to let rtos do some work
+        // The graph shares the strip with the main color, on LEDs of its own
+        if let Some(pixels) =
+            graph.update(now, wall_clock_ms(), &history, &color_map, lamp.led_count())
+        {
+            lamp.set_pixels(&pixels);
+        }
+
+        // Move any animation on to the next frame
+        lamp.tick(uptime_ms());
+
+        // 10 ms delay to let rtos do some work
         FreeRtos::delay_ms(10);
     }
 }
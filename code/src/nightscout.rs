@@ -0,0 +1,419 @@
+pub mod nightscout {
+    use crate::glucose::glucose::{GlucoseError, GlucoseReading, GlucoseSource, GlucoseTrend};
+    use crate::http::http::HttpTransport;
+    use crate::server::server::{ServableData, ServableDataReq, ServableDataRsp, ServerData};
+    use crate::storage::storage::Storable;
+    use log::info;
+    use serde::de::{DeserializeOwned, IgnoredAny};
+    use serde::{Deserialize, Serialize};
+    use sha1_smol::Sha1;
+    use std::sync::mpsc;
+
+    pub const STATUS_ENDPOINT: &str = "api/v1/status.json";
+    pub const ENTRIES_ENDPOINT: &str = "api/v1/entries/sgv.json";
+
+    // One reading every 5 minutes for 24 hours
+    pub const HISTORY_COUNT: usize = 288;
+
+    #[derive(Deserialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    struct NightscoutEntry {
+        sgv: Option<isize>,
+        date: i64,
+        direction: Option<String>,
+        trend: Option<u8>,
+        utc_offset: Option<i16>,
+    }
+
+    impl NightscoutEntry {
+        fn to_reading(&self) -> Option<GlucoseReading> {
+            let trend = match (&self.direction, self.trend) {
+                (Some(direction), _) => GlucoseTrend::from_name(direction),
+                (None, Some(trend)) => GlucoseTrend::from_index(trend),
+                (None, None) => GlucoseTrend::NoTrend,
+            };
+
+            self.sgv.map(|value| GlucoseReading {
+                time: self.date,
+                value,
+                trend,
+                system_offset: None,
+                display_offset: self.utc_offset,
+            })
+        }
+    }
+
+    // Tokens are usually URL safe already, but nothing stops a site issuing one
+    // that isn't
+    fn percent_encode(value: &str) -> String {
+        value
+            .bytes()
+            .map(|byte| match byte {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                    (byte as char).to_string()
+                }
+                _ => format!("%{:02X}", byte),
+            })
+            .collect()
+    }
+
+    pub struct Nightscout<T: HttpTransport> {
+        transport: T,
+        url: Option<String>,
+        // Either the site's API secret or an access token, depending on how the
+        // site is set up. Public sites need neither.
+        secret: Option<String>,
+        token: Option<String>,
+        server_channel: Option<mpsc::Receiver<ServableDataReq>>,
+        save_data: bool,
+    }
+
+    impl<T: HttpTransport> Nightscout<T> {
+        pub fn new(transport: T) -> Self {
+            Nightscout {
+                transport,
+                url: None,
+                secret: None,
+                token: None,
+                server_channel: None,
+                save_data: false,
+            }
+        }
+
+        pub fn transport(&self) -> &T {
+            &self.transport
+        }
+
+        // The site to read from, if one has been set
+        fn site(&self) -> Option<&str> {
+            self.url
+                .as_deref()
+                .map(str::trim)
+                .filter(|url| !url.is_empty())
+        }
+
+        pub fn get_glucose(&mut self, count: usize) -> Result<Vec<GlucoseReading>, GlucoseError> {
+            let entries: Vec<NightscoutEntry> =
+                self.get(&format!("{}?count={}", ENTRIES_ENDPOINT, count))?;
+
+            Ok(entries
+                .iter()
+                .filter_map(|entry| entry.to_reading())
+                .collect())
+        }
+
+        fn get<D: DeserializeOwned>(&mut self, endpoint: &str) -> Result<D, GlucoseError> {
+            let Some(base) = self.site() else {
+                return Err(GlucoseError::NoCredentials);
+            };
+
+            let mut url = format!("{}/{}", base.trim_end_matches('/'), endpoint);
+            if let Some(token) = &self.token {
+                let separator = if url.contains('?') { '&' } else { '?' };
+                url = format!("{}{}token={}", url, separator, percent_encode(token));
+            }
+
+            // The API secret goes over the wire as its SHA1 hex digest
+            let hashed_secret = self
+                .secret
+                .as_ref()
+                .map(|secret| Sha1::from(secret).digest().to_string());
+            let mut headers = Vec::new();
+            if let Some(hashed_secret) = &hashed_secret {
+                headers.push(("api-secret", hashed_secret.as_str()));
+            }
+
+            let response = self
+                .transport
+                .get(&url, &headers)
+                .map_err(|e| GlucoseError::Unavailable(e.to_string()))?;
+
+            match response.status {
+                200 => response
+                    .json()
+                    .map_err(|e| GlucoseError::Unavailable(e.to_string())),
+                401 | 403 => Err(GlucoseError::AuthFailed(format!(
+                    "nightscout refused access ({})",
+                    response.status
+                ))),
+                429 => Err(GlucoseError::RateLimited),
+                status => Err(GlucoseError::Unavailable(format!(
+                    "unexpected http status {}",
+                    status
+                ))),
+            }
+        }
+
+        pub fn need_to_save(&self) -> bool {
+            self.save_data
+        }
+
+        pub fn saved(&mut self) {
+            self.save_data = false;
+        }
+    }
+
+    impl<T: HttpTransport> GlucoseSource for Nightscout<T> {
+        fn has_creds(&self) -> bool {
+            self.site().is_some()
+        }
+
+        fn reset_creds(&mut self) {
+            self.url = None;
+            self.secret = None;
+            self.token = None;
+            self.save_data = true;
+        }
+
+        // Nightscout has no sessions, just make sure the site is there and lets us in
+        fn connect(&mut self) -> Result<(), GlucoseError> {
+            // Only checking the site answers, whatever it says about itself
+            self.get::<IgnoredAny>(STATUS_ENDPOINT).map(|_| ())
+        }
+
+        fn get_latest_glucose(&mut self) -> Result<GlucoseReading, GlucoseError> {
+            self.get_glucose(1)?
+                .first()
+                .copied()
+                .ok_or(GlucoseError::NoReadings)
+        }
+
+        fn get_glucose_history(&mut self) -> Result<Vec<GlucoseReading>, GlucoseError> {
+            self.get_glucose(HISTORY_COUNT)
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct NvsNightscoutState {
+        url: Option<String>,
+        secret: Option<String>,
+        token: Option<String>,
+    }
+
+    impl<T: HttpTransport> Storable for Nightscout<T> {
+        fn store_tag(&self) -> &str {
+            "nightscout"
+        }
+
+        fn store_data(&self) -> Vec<u8> {
+            let data = NvsNightscoutState {
+                url: self.url.to_owned(),
+                secret: self.secret.to_owned(),
+                token: self.token.to_owned(),
+            };
+
+            serde_json::to_string(&data).unwrap().into_bytes()
+        }
+
+        fn recall_data(&mut self, data: &[u8]) {
+            let nvs_state = serde_json::from_slice::<NvsNightscoutState>(data).unwrap();
+            self.url = nvs_state.url;
+            self.secret = nvs_state.secret;
+            self.token = nvs_state.token;
+            self.save_data = false;
+        }
+    }
+
+    impl<T: HttpTransport> ServableData for Nightscout<T> {
+        fn get_channel(&mut self) -> mpsc::Sender<ServableDataReq> {
+            let (tx, rx) = mpsc::channel::<ServableDataReq>();
+            self.server_channel = Some(rx);
+            tx
+        }
+
+        fn handle_server_req(&mut self) {
+            if let Some(channel) = &self.server_channel {
+                if let Ok(req) = channel.try_recv() {
+                    info!("nightscout got a request from server");
+
+                    if let ServableDataReq::Get(back_channel) = &req {
+                        info!("Sending nightscout state to server");
+                        let mut rsp = ServerData::new();
+                        rsp.nightscout_url_stored = Some(self.site().is_some());
+                        rsp.nightscout_secret_stored = Some(self.secret.is_some());
+                        rsp.nightscout_token_stored = Some(self.token.is_some());
+                        back_channel.send(ServableDataRsp::Data(rsp)).unwrap();
+                    }
+
+                    if let ServableDataReq::Set(update) = &req {
+                        if let Some(url) = &update.nightscout_url {
+                            if url.trim().is_empty() {
+                                info!("Ignoring empty nightscout url");
+                            } else {
+                                self.url = Some(url.clone());
+                                self.save_data = true;
+                            }
+                        }
+
+                        if let Some(secret) = &update.nightscout_secret {
+                            self.secret = Some(secret.clone());
+                            self.save_data = true;
+                        }
+
+                        if let Some(token) = &update.nightscout_token {
+                            self.token = Some(token.clone());
+                            self.save_data = true;
+                        }
+                    }
+
+                    if let ServableDataReq::Reset = &req {
+                        self.url = None;
+                        self.secret = None;
+                        self.token = None;
+                        self.save_data = true;
+                    }
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::http::http::ScriptedTransport;
+        use crate::server::server::ServerUpdate;
+
+        const ENTRIES: &str = r#"[
+            {"sgv": 123, "date": 1700000600000, "direction": "SingleUp", "trend": 6, "utcOffset": -300},
+            {"sgv": 118, "date": 1700000300000, "trend": 2},
+            {"date": 1700000000000, "direction": "Flat"},
+            {"sgv": 110, "date": 1699999700000}
+        ]"#;
+
+        fn nightscout(transport: ScriptedTransport) -> Nightscout<ScriptedTransport> {
+            let mut nightscout = Nightscout::new(transport);
+            nightscout.url = Some("https://cgm.example.com/".into());
+            nightscout
+        }
+
+        fn update(nightscout: &mut Nightscout<ScriptedTransport>, update: ServerUpdate) {
+            let channel = nightscout.get_channel();
+            channel.send(ServableDataReq::Set(update)).unwrap();
+            nightscout.handle_server_req();
+        }
+
+        #[test]
+        fn parses_entries() {
+            let mut nightscout = nightscout(ScriptedTransport::new().respond(200, ENTRIES));
+
+            let readings = nightscout.get_glucose_history().unwrap();
+            assert_eq!(
+                nightscout.transport().requests()[0].0,
+                format!(
+                    "https://cgm.example.com/{}?count={}",
+                    ENTRIES_ENDPOINT, HISTORY_COUNT
+                )
+            );
+
+            // The entry without an sgv is skipped
+            assert_eq!(readings.len(), 3);
+            assert_eq!(readings[0].value, 123);
+            assert_eq!(readings[0].time, 1700000600000);
+            assert_eq!(readings[0].display_offset, Some(-300));
+            // The direction wins over the numeric trend
+            assert!(matches!(readings[0].trend, GlucoseTrend::SingleUp));
+            assert!(matches!(readings[1].trend, GlucoseTrend::SingleUp));
+            assert!(matches!(readings[2].trend, GlucoseTrend::NoTrend));
+        }
+
+        #[test]
+        fn latest_reading_needs_an_sgv() {
+            let body = r#"[{"date": 1700000000000, "direction": "Flat"}]"#;
+            let mut nightscout = nightscout(ScriptedTransport::new().respond(200, body));
+
+            assert_eq!(
+                nightscout.get_latest_glucose().unwrap_err(),
+                GlucoseError::NoReadings
+            );
+        }
+
+        #[test]
+        fn sends_the_hashed_secret_and_the_token() {
+            let transport = ScriptedTransport::new().respond(200, r#"{"status": "ok"}"#);
+            let mut nightscout = nightscout(transport);
+            nightscout.secret = Some("my-api-secret".into());
+            nightscout.token = Some("reader-1a2b&c d".into());
+
+            nightscout.connect().unwrap();
+            let transport = nightscout.transport();
+            assert_eq!(
+                transport.requests()[0].0,
+                format!(
+                    "https://cgm.example.com/{}?token=reader-1a2b%26c%20d",
+                    STATUS_ENDPOINT
+                )
+            );
+            assert_eq!(
+                transport.header(0, "api-secret"),
+                Some("402920da22450050bc374bb5ac2e7579c54b5dec")
+            );
+        }
+
+        #[test]
+        fn token_joins_an_existing_query() {
+            let mut nightscout = nightscout(ScriptedTransport::new().respond(200, "[]"));
+            nightscout.token = Some("reader".into());
+
+            nightscout.get_glucose(1).unwrap();
+            assert!(nightscout.transport().requests()[0]
+                .0
+                .ends_with("?count=1&token=reader"));
+            assert_eq!(nightscout.transport().header(0, "api-secret"), None);
+        }
+
+        #[test]
+        fn classifies_refusals() {
+            let cases = [
+                (
+                    401,
+                    GlucoseError::AuthFailed("nightscout refused access (401)".into()),
+                ),
+                (
+                    403,
+                    GlucoseError::AuthFailed("nightscout refused access (403)".into()),
+                ),
+                (429, GlucoseError::RateLimited),
+                (
+                    500,
+                    GlucoseError::Unavailable("unexpected http status 500".into()),
+                ),
+            ];
+
+            for (status, expected) in cases {
+                let mut nightscout = nightscout(ScriptedTransport::new().respond(status, ""));
+                assert_eq!(nightscout.connect().unwrap_err(), expected, "{}", status);
+            }
+        }
+
+        #[test]
+        fn empty_url_is_not_a_site() {
+            let mut nightscout = Nightscout::new(ScriptedTransport::new());
+            update(
+                &mut nightscout,
+                ServerUpdate {
+                    nightscout_url: Some("  ".into()),
+                    ..ServerUpdate::default()
+                },
+            );
+            assert!(!nightscout.has_creds());
+            assert!(!nightscout.need_to_save());
+            assert_eq!(
+                nightscout.connect().unwrap_err(),
+                GlucoseError::NoCredentials
+            );
+
+            update(
+                &mut nightscout,
+                ServerUpdate {
+                    nightscout_url: Some("https://cgm.example.com".into()),
+                    ..ServerUpdate::default()
+                },
+            );
+            assert!(nightscout.has_creds());
+
+            // Stored before empty URLs were refused
+            nightscout.url = Some(String::new());
+            assert!(!nightscout.has_creds());
+        }
+    }
+}
@@ -0,0 +1,256 @@
+pub mod scheduler {
+    use crate::glucose::glucose::GlucoseReading;
+    use crate::server::server::{ServableData, ServableDataReq, ServableDataRsp, ServerData};
+    use log::info;
+    use std::sync::mpsc;
+
+    // CGMs produce a reading every 5 minutes
+    pub const READING_INTERVAL: u64 = 300;
+    // Typical delay between the sensor taking a reading and it showing up online
+    pub const UPLOAD_LAG: u64 = 20;
+    // Start polling this long before a reading is expected...
+    pub const WINDOW_LEAD: u64 = 10;
+    // ...and keep polling densely for this long after
+    pub const WINDOW_LENGTH: u64 = 90;
+    pub const DENSE_INTERVAL: u64 = 10;
+    // Poll rate once the window has passed without a new reading
+    pub const IDLE_INTERVAL: u64 = 60;
+    pub const MAX_BACKOFF: u64 = 600;
+
+    // Decides when the glucose source should next be asked for a reading, based on
+    // when the last reading was taken
+    pub struct PollScheduler {
+        next_poll: u64,
+        // Local time the next reading is expected to be available
+        expected: Option<u64>,
+        last_reading_time: Option<i64>,
+        failures: u32,
+        server_channel: Option<mpsc::Receiver<ServableDataReq>>,
+    }
+
+    impl Default for PollScheduler {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl PollScheduler {
+        pub fn new() -> Self {
+            PollScheduler {
+                next_poll: 0,
+                expected: None,
+                last_reading_time: None,
+                failures: 0,
+                server_channel: None,
+            }
+        }
+
+        pub fn due(&self, now: u64) -> bool {
+            now >= self.next_poll
+        }
+
+        pub fn next_poll(&self) -> u64 {
+            self.next_poll
+        }
+
+        // Forget what we know and poll right away, e.g. after switching sources
+        pub fn reset(&mut self) {
+            self.next_poll = 0;
+            self.expected = None;
+            self.last_reading_time = None;
+            self.failures = 0;
+        }
+
+        // A poll succeeded. `wall_now` is the current time in ms since the Unix epoch,
+        // if the clock is set, and lets us work out how old the reading already is.
+        pub fn reading_received(
+            &mut self,
+            now: u64,
+            wall_now: Option<i64>,
+            reading: &GlucoseReading,
+        ) {
+            self.failures = 0;
+
+            if self.last_reading_time != Some(reading.time) {
+                self.last_reading_time = Some(reading.time);
+
+                // Without a clock, assume the reading only just got uploaded
+                let age = wall_now
+                    .map(|wall_now| ((wall_now - reading.time) / 1000).max(0) as u64)
+                    .unwrap_or(UPLOAD_LAG)
+                    .min(READING_INTERVAL);
+
+                let expected = now - age.min(now) + READING_INTERVAL + UPLOAD_LAG;
+                self.expected = Some(expected);
+                self.next_poll = (expected - WINDOW_LEAD).max(now + DENSE_INTERVAL);
+            } else {
+                self.no_new_reading(now);
+            }
+
+            info!("Next glucose poll in {}s", self.next_poll - now);
+        }
+
+        // The source answered, but had nothing newer than last time
+        pub fn no_new_reading(&mut self, now: u64) {
+            self.failures = 0;
+
+            let in_window = match self.expected {
+                Some(expected) => now < expected + WINDOW_LENGTH,
+                None => false,
+            };
+
+            self.next_poll = now
+                + if in_window {
+                    DENSE_INTERVAL
+                } else {
+                    IDLE_INTERVAL
+                };
+        }
+
+        // The poll failed, back off exponentially
+        pub fn failed(&mut self, now: u64) {
+            self.failures += 1;
+
+            let backoff = DENSE_INTERVAL
+                .saturating_mul(1 << self.failures.min(16))
+                .min(MAX_BACKOFF);
+            self.next_poll = now + backoff;
+
+            info!(
+                "Glucose poll failed {} times, retrying in {}s",
+                self.failures, backoff
+            );
+        }
+    }
+
+    impl ServableData for PollScheduler {
+        fn get_channel(&mut self) -> mpsc::Sender<ServableDataReq> {
+            let (tx, rx) = mpsc::channel::<ServableDataReq>();
+            self.server_channel = Some(rx);
+            tx
+        }
+
+        fn handle_server_req(&mut self) {
+            if let Some(channel) = &self.server_channel {
+                if let Ok(req) = channel.try_recv() {
+                    info!("scheduler got a request from server");
+
+                    if let ServableDataReq::Get(back_channel) = &req {
+                        info!("Sending scheduler state to server");
+                        let mut rsp = ServerData::new();
+                        rsp.next_poll = Some(self.next_poll);
+                        rsp.poll_failures = Some(self.failures);
+                        back_channel.send(ServableDataRsp::Data(rsp)).unwrap();
+                    }
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const TAKEN_AT: i64 = 1_700_000_000_000;
+
+        fn reading(time: i64) -> GlucoseReading {
+            GlucoseReading {
+                time,
+                value: 120,
+                ..GlucoseReading::new()
+            }
+        }
+
+        #[test]
+        fn polls_just_before_the_next_reading_is_due() {
+            let mut scheduler = PollScheduler::new();
+            // Taken a minute ago, so the next one should be up 260s from now
+            scheduler.reading_received(1000, Some(TAKEN_AT + 60_000), &reading(TAKEN_AT));
+
+            assert_eq!(scheduler.expected, Some(1260));
+            assert_eq!(scheduler.next_poll(), 1260 - WINDOW_LEAD);
+            assert!(!scheduler.due(1249));
+            assert!(scheduler.due(1250));
+        }
+
+        #[test]
+        fn assumes_the_usual_upload_lag_without_a_clock() {
+            let mut scheduler = PollScheduler::new();
+            scheduler.reading_received(1000, None, &reading(TAKEN_AT));
+
+            assert_eq!(scheduler.expected, Some(1000 + READING_INTERVAL));
+            assert_eq!(scheduler.next_poll(), 1000 + READING_INTERVAL - WINDOW_LEAD);
+        }
+
+        #[test]
+        fn old_readings_are_polled_for_densely() {
+            let mut scheduler = PollScheduler::new();
+            scheduler.reading_received(1000, Some(TAKEN_AT + 3_600_000), &reading(TAKEN_AT));
+
+            assert_eq!(scheduler.next_poll(), 1000 + DENSE_INTERVAL);
+        }
+
+        #[test]
+        fn the_same_reading_again_is_not_new() {
+            let mut scheduler = PollScheduler::new();
+            scheduler.reading_received(1000, Some(TAKEN_AT + 60_000), &reading(TAKEN_AT));
+            scheduler.reading_received(1260, Some(TAKEN_AT + 320_000), &reading(TAKEN_AT));
+
+            assert_eq!(scheduler.expected, Some(1260));
+            assert_eq!(scheduler.next_poll(), 1260 + DENSE_INTERVAL);
+        }
+
+        #[test]
+        fn polls_densely_inside_the_window() {
+            let mut scheduler = PollScheduler::new();
+            scheduler.reading_received(1000, Some(TAKEN_AT + 60_000), &reading(TAKEN_AT));
+
+            scheduler.no_new_reading(1300);
+            assert_eq!(scheduler.next_poll(), 1300 + DENSE_INTERVAL);
+
+            // Last dense poll
+            scheduler.no_new_reading(1260 + WINDOW_LENGTH - 1);
+            assert_eq!(
+                scheduler.next_poll(),
+                1260 + WINDOW_LENGTH - 1 + DENSE_INTERVAL
+            );
+        }
+
+        #[test]
+        fn polls_lazily_outside_the_window() {
+            let mut scheduler = PollScheduler::new();
+            scheduler.no_new_reading(1000);
+            assert_eq!(scheduler.next_poll(), 1000 + IDLE_INTERVAL);
+
+            scheduler.reading_received(1000, Some(TAKEN_AT + 60_000), &reading(TAKEN_AT));
+            scheduler.no_new_reading(1260 + WINDOW_LENGTH);
+            assert_eq!(scheduler.next_poll(), 1260 + WINDOW_LENGTH + IDLE_INTERVAL);
+        }
+
+        #[test]
+        fn backs_off_up_to_the_maximum() {
+            let mut scheduler = PollScheduler::new();
+            let mut backoffs = Vec::new();
+            for _ in 0..40 {
+                scheduler.failed(1000);
+                backoffs.push(scheduler.next_poll() - 1000);
+            }
+
+            assert_eq!(backoffs[..6], [20, 40, 80, 160, 320, MAX_BACKOFF]);
+            assert!(backoffs[6..].iter().all(|&backoff| backoff == MAX_BACKOFF));
+        }
+
+        #[test]
+        fn a_reading_ends_the_backoff() {
+            let mut scheduler = PollScheduler::new();
+            for _ in 0..5 {
+                scheduler.failed(1000);
+            }
+            scheduler.reading_received(1000, None, &reading(TAKEN_AT));
+            assert_eq!(scheduler.failures, 0);
+
+            scheduler.failed(1000);
+            assert_eq!(scheduler.next_poll(), 1000 + 2 * DENSE_INTERVAL);
+        }
+    }
+}
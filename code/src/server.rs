@@ -1,29 +1,19 @@
 pub mod server {
-    use embedded_svc::{
-        http::{Headers, Method},
-        io::{Read, Write},
-    };
-    use esp_idf_svc::http::server::EspHttpServer;
-    use log::info;
+    use crate::animation::animation::{AnimationPeriods, AnimationPeriodsUpdate};
+    use crate::colormap::colormap::ColorStop;
+    use crate::dexcom::dexcom::DexcomRegion;
+    use crate::glucose::glucose::{GlucoseUnit, SourceKind};
+    use crate::graph::graph::GraphSettings;
+    use crate::stale::stale::{ReadingStage, StaleThresholds};
+    use crate::status::status::LampStatus;
+    use crate::time::time::ClockSource;
+    use crate::trend::trend::{TrendStyles, TrendStylesUpdate};
+    use rgb_led::Chip;
     use serde::{Deserialize, Serialize};
     use std::sync::mpsc;
     use std::sync::mpsc::Sender;
-    //use std::sync::{Arc, Mutex};
 
-    static INDEX_HTML: &str = include_str!("index.html");
-
-    const STACK_SIZE: usize = 10240;
-
-    // Need lots of stack to parse JSON
-    // Max payload length
-    const MAX_LEN: usize = 1024;
-
-    const API_VER: &str = "v1";
-    const API_STATE: &str = "state";
-    const API_SET: &str = "set";
-    const API_RESET: &str = "reset";
-
-    #[derive(Debug, Deserialize, Serialize, Clone)]
+    #[derive(Debug, Deserialize, Serialize, Clone, Default)]
     pub struct ServerUpdate {
         pub brightness: Option<u8>,
         pub on: Option<bool>,
@@ -31,6 +21,24 @@ pub mod server {
         pub ap_psk: Option<String>,
         pub dexcom_user: Option<String>,
         pub dexcom_pass: Option<String>,
+        pub dexcom_region: Option<DexcomRegion>,
+        pub nightscout_url: Option<String>,
+        pub nightscout_secret: Option<String>,
+        pub nightscout_token: Option<String>,
+        pub libre_user: Option<String>,
+        pub libre_pass: Option<String>,
+        pub glucose_source: Option<SourceKind>,
+        pub stale_thresholds: Option<StaleThresholds>,
+        pub ntp_servers: Option<Vec<String>>,
+        pub glucose_unit: Option<GlucoseUnit>,
+        // Only set through the color map endpoint, which validates it first
+        #[serde(skip_deserializing)]
+        pub color_map: Option<Vec<ColorStop>>,
+        pub animation_periods: Option<AnimationPeriodsUpdate>,
+        pub trend_styles: Option<TrendStylesUpdate>,
+        pub led_count: Option<usize>,
+        pub history_graph: Option<GraphSettings>,
+        pub led_chip: Option<Chip>,
     }
 
     #[derive(Debug, Deserialize, Serialize)]
@@ -41,11 +49,47 @@ pub mod server {
         pub ap_psk_stored: Option<bool>,
         pub dexcom_user_stored: Option<bool>,
         pub dexcom_pass_stored: Option<bool>,
+        pub dexcom_region: Option<DexcomRegion>,
+        pub dexcom_session_age: Option<u64>,
+        pub dexcom_relogins: Option<u32>,
+        pub nightscout_url_stored: Option<bool>,
+        pub nightscout_secret_stored: Option<bool>,
+        pub nightscout_token_stored: Option<bool>,
+        pub libre_user_stored: Option<bool>,
+        pub libre_pass_stored: Option<bool>,
+        pub glucose_source: Option<SourceKind>,
         pub bat_attached: Option<bool>,
         pub bat_charging: Option<bool>,
         pub bat_capacity: Option<f32>,
         pub uptime: Option<u64>,
         pub temp: Option<f32>,
+        pub next_poll: Option<u64>,
+        pub poll_failures: Option<u32>,
+        pub history_len: Option<usize>,
+        pub history_gaps: Option<usize>,
+        pub last_reading_time: Option<i64>,
+        pub reading_age: Option<u64>,
+        pub reading_stage: Option<ReadingStage>,
+        pub stale_thresholds: Option<StaleThresholds>,
+        pub wall_time: Option<i64>,
+        pub clock_source: Option<ClockSource>,
+        pub time_sync_age: Option<u64>,
+        pub ntp_servers: Option<Vec<String>>,
+        pub glucose_unit: Option<GlucoseUnit>,
+        pub glucose: Option<f32>,
+        pub color_map: Option<Vec<ColorStop>>,
+        pub animation_periods: Option<AnimationPeriods>,
+        pub trend_styles: Option<TrendStyles>,
+        pub led_count: Option<usize>,
+        pub history_graph: Option<GraphSettings>,
+        pub led_chip: Option<Chip>,
+        pub status: Option<LampStatus>,
+    }
+
+    impl Default for ServerData {
+        fn default() -> Self {
+            Self::new()
+        }
     }
 
     impl ServerData {
@@ -58,11 +102,41 @@ pub mod server {
                 ap_psk_stored: None,
                 dexcom_user_stored: None,
                 dexcom_pass_stored: None,
+                dexcom_region: None,
+                dexcom_session_age: None,
+                dexcom_relogins: None,
+                nightscout_url_stored: None,
+                nightscout_secret_stored: None,
+                nightscout_token_stored: None,
+                libre_user_stored: None,
+                libre_pass_stored: None,
+                glucose_source: None,
                 bat_attached: None,
                 bat_charging: None,
                 bat_capacity: None,
                 uptime: None,
                 temp: None,
+                next_poll: None,
+                poll_failures: None,
+                history_len: None,
+                history_gaps: None,
+                last_reading_time: None,
+                reading_age: None,
+                reading_stage: None,
+                stale_thresholds: None,
+                wall_time: None,
+                clock_source: None,
+                time_sync_age: None,
+                ntp_servers: None,
+                glucose_unit: None,
+                glucose: None,
+                color_map: None,
+                animation_periods: None,
+                trend_styles: None,
+                led_count: None,
+                history_graph: None,
+                led_chip: None,
+                status: None,
             }
         }
 
@@ -73,14 +147,54 @@ pub mod server {
             self.ap_psk_stored = self.ap_psk_stored.or(other.ap_psk_stored);
             self.dexcom_user_stored = self.dexcom_user_stored.or(other.dexcom_user_stored);
             self.dexcom_pass_stored = self.dexcom_pass_stored.or(other.dexcom_pass_stored);
+            self.dexcom_region = self.dexcom_region.or(other.dexcom_region);
+            self.dexcom_session_age = self.dexcom_session_age.or(other.dexcom_session_age);
+            self.dexcom_relogins = self.dexcom_relogins.or(other.dexcom_relogins);
+            self.nightscout_url_stored = self.nightscout_url_stored.or(other.nightscout_url_stored);
+            self.nightscout_secret_stored = self
+                .nightscout_secret_stored
+                .or(other.nightscout_secret_stored);
+            self.nightscout_token_stored = self
+                .nightscout_token_stored
+                .or(other.nightscout_token_stored);
+            self.libre_user_stored = self.libre_user_stored.or(other.libre_user_stored);
+            self.libre_pass_stored = self.libre_pass_stored.or(other.libre_pass_stored);
+            self.glucose_source = self.glucose_source.or(other.glucose_source);
             self.bat_attached = self.bat_attached.or(other.bat_attached);
             self.bat_charging = self.bat_charging.or(other.bat_charging);
             self.bat_capacity = self.bat_capacity.or(other.bat_capacity);
             self.uptime = self.uptime.or(other.uptime);
             self.temp = self.temp.or(other.temp);
+            self.next_poll = self.next_poll.or(other.next_poll);
+            self.poll_failures = self.poll_failures.or(other.poll_failures);
+            self.history_len = self.history_len.or(other.history_len);
+            self.history_gaps = self.history_gaps.or(other.history_gaps);
+            self.last_reading_time = self.last_reading_time.or(other.last_reading_time);
+            self.reading_age = self.reading_age.or(other.reading_age);
+            self.reading_stage = self.reading_stage.or(other.reading_stage);
+            self.stale_thresholds = self.stale_thresholds.or(other.stale_thresholds);
+            self.wall_time = self.wall_time.or(other.wall_time);
+            self.clock_source = self.clock_source.or(other.clock_source);
+            self.time_sync_age = self.time_sync_age.or(other.time_sync_age);
+            self.glucose_unit = self.glucose_unit.or(other.glucose_unit);
+            self.glucose = self.glucose.or(other.glucose);
+            self.animation_periods = self.animation_periods.or(other.animation_periods);
+            self.trend_styles = self.trend_styles.or(other.trend_styles);
+            self.led_count = self.led_count.or(other.led_count);
+            self.history_graph = self.history_graph.or(other.history_graph);
+            self.led_chip = self.led_chip.or(other.led_chip);
+            self.status = self.status.or(other.status);
+            if self.ntp_servers.is_none() {
+                self.ntp_servers = other.ntp_servers.clone();
+            }
+            if self.color_map.is_none() {
+                self.color_map = other.color_map.clone();
+            }
         }
     }
 
+    // Only ever a handful of these in flight, so boxing the big variants isn't worth it
+    #[allow(clippy::large_enum_variant)]
     #[derive(Debug)]
     pub enum ServableDataReq {
         Set(ServerUpdate),
@@ -88,178 +202,292 @@ pub mod server {
         Reset,
     }
 
+    #[allow(clippy::large_enum_variant)]
     pub enum ServableDataRsp {
         Data(ServerData),
         Error,
     }
 
-    pub struct Server<'a> {
-        server: Option<EspHttpServer<'a>>,
-        data_channels: Vec<Sender<ServableDataReq>>,
+    pub trait ServableData {
+        fn get_channel(&mut self) -> Sender<ServableDataReq>;
+        fn handle_server_req(&mut self);
     }
 
-    impl<'a> Server<'a> {
-        pub fn new() -> Self {
-            Server {
-                server: None,
-                data_channels: Vec::new(),
-            }
+    #[cfg(target_os = "espidf")]
+    pub use esp::Server;
+
+    // The web interface, served by ESP-IDF
+    #[cfg(target_os = "espidf")]
+    mod esp {
+        use super::{ServableData, ServableDataReq, ServableDataRsp, ServerData, ServerUpdate};
+        use crate::colormap::colormap::{validate, ColorStop, ColorStopUpdate};
+        use crate::glucose::glucose::GlucoseUnit;
+        use embedded_svc::{
+            http::{Headers, Method},
+            io::{Read, Write},
+        };
+        use esp_idf_svc::http::server::EspHttpServer;
+        use log::info;
+        use std::sync::mpsc;
+        use std::sync::mpsc::Sender;
+        //use std::sync::{Arc, Mutex};
+
+        static INDEX_HTML: &str = include_str!("index.html");
+
+        const STACK_SIZE: usize = 10240;
+
+        // Need lots of stack to parse JSON
+        // Max payload length
+        const MAX_LEN: usize = 1024;
+
+        const API_VER: &str = "v1";
+        const API_STATE: &str = "state";
+        const API_SET: &str = "set";
+        const API_RESET: &str = "reset";
+        const API_COLOR_MAP: &str = "colormap";
+
+        pub struct Server<'a> {
+            server: Option<EspHttpServer<'a>>,
+            data_channels: Vec<Sender<ServableDataReq>>,
+            // Whoever owns the display unit, also in data_channels
+            unit_channel: Option<Sender<ServableDataReq>>,
         }
 
-        pub fn add_data_channel(&mut self, obj: &mut impl ServableData) {
-            self.data_channels.push(obj.get_channel())
-        }
+        impl<'a> Server<'a> {
+            pub fn new() -> Self {
+                Server {
+                    server: None,
+                    data_channels: Vec::new(),
+                    unit_channel: None,
+                }
+            }
+
+            pub fn add_data_channel(&mut self, obj: &mut impl ServableData) {
+                self.data_channels.push(obj.get_channel())
+            }
+
+            // Like add_data_channel(), for the object that owns the display unit. The
+            // color map endpoint asks it alone what bare levels are in.
+            pub fn add_unit_channel(&mut self, obj: &mut impl ServableData) {
+                let channel = obj.get_channel();
+                self.unit_channel = Some(channel.clone());
+                self.data_channels.push(channel);
+            }
+
+            // Start server listeners
+            pub fn start(&mut self) -> anyhow::Result<()> {
+                let server_configuration = esp_idf_svc::http::server::Configuration {
+                    stack_size: STACK_SIZE,
+                    ..Default::default()
+                };
 
-        // Start server listeners
-        pub fn start(&mut self) -> anyhow::Result<()> {
-            let server_configuration = esp_idf_svc::http::server::Configuration {
-                stack_size: STACK_SIZE,
-                ..Default::default()
-            };
-
-            self.server = Some(EspHttpServer::new(&server_configuration).unwrap());
-
-            // Listener: serve the config page
-            self.server
-                .as_mut()
-                .unwrap()
-                .fn_handler("/", Method::Get, |req| {
-                    req.into_ok_response()?
-                        .write_all(INDEX_HTML.as_bytes())
-                        .map(|_| ())
-                })?;
-
-            //Listener: Handle new settings from the web app
-            {
-                let data_channels = self.data_channels.clone();
+                self.server = Some(EspHttpServer::new(&server_configuration).unwrap());
+
+                // Listener: serve the config page
                 self.server
                     .as_mut()
                     .unwrap()
-                    .fn_handler::<anyhow::Error, _>(
-                        &format!("/api/{}/{}", API_VER, API_SET),
-                        Method::Post,
-                        move |mut req| {
-                            let len = req.content_len().unwrap_or(0) as usize;
-
-                            if len > MAX_LEN {
-                                req.into_status_response(413)?
-                                    .write_all("Request too big".as_bytes())?;
-                                return Ok(());
-                            }
-
-                            let mut buf = vec![0; len];
-                            req.read_exact(&mut buf)?;
-                            let mut resp = req.into_ok_response()?;
-
-                            let msg = serde_json::from_slice::<ServerUpdate>(&buf);
-                            match msg {
-                                Ok(form) => {
-                                    Server::send_server_update(&data_channels, &form);
-                                    write!(resp, "New settings applied")?;
+                    .fn_handler("/", Method::Get, |req| {
+                        req.into_ok_response()?
+                            .write_all(INDEX_HTML.as_bytes())
+                            .map(|_| ())
+                    })?;
+
+                //Listener: Handle new settings from the web app
+                {
+                    let data_channels = self.data_channels.clone();
+                    self.server
+                        .as_mut()
+                        .unwrap()
+                        .fn_handler::<anyhow::Error, _>(
+                            &format!("/api/{}/{}", API_VER, API_SET),
+                            Method::Post,
+                            move |mut req| {
+                                let len = req.content_len().unwrap_or(0) as usize;
+
+                                if len > MAX_LEN {
+                                    req.into_status_response(413)?
+                                        .write_all("Request too big".as_bytes())?;
+                                    return Ok(());
                                 }
-                                Err(e) => {
-                                    info!("Error parsing SET data: {}", e);
-                                    resp.write_all("JSON error".as_bytes())?;
+
+                                let mut buf = vec![0; len];
+                                req.read_exact(&mut buf)?;
+                                let mut resp = req.into_ok_response()?;
+
+                                let msg = serde_json::from_slice::<ServerUpdate>(&buf);
+                                match msg {
+                                    Ok(form) => {
+                                        Server::send_server_update(&data_channels, &form);
+                                        write!(resp, "New settings applied")?;
+                                    }
+                                    Err(e) => {
+                                        info!("Error parsing SET data: {}", e);
+                                        resp.write_all("JSON error".as_bytes())?;
+                                    }
                                 }
-                            }
 
-                            Ok(())
-                        },
-                    )?;
-            }
+                                Ok(())
+                            },
+                        )?;
+                }
 
-            // Listener: Serve the device's status when on request
-            {
-                let data_channels = self.data_channels.clone();
-                self.server.as_mut().unwrap().fn_handler(
-                    &format!("/api/{}/{}", API_VER, API_STATE),
-                    Method::Get,
-                    move |req| {
-                        info!("Get request on /state!");
+                // Listener: Replace the glucose color map
+                {
+                    let data_channels = self.data_channels.clone();
+                    let unit_channel = self.unit_channel.clone();
+                    self.server
+                        .as_mut()
+                        .unwrap()
+                        .fn_handler::<anyhow::Error, _>(
+                            &format!("/api/{}/{}", API_VER, API_COLOR_MAP),
+                            Method::Post,
+                            move |mut req| {
+                                let len = req.content_len().unwrap_or(0) as usize;
+
+                                if len > MAX_LEN {
+                                    req.into_status_response(413)?
+                                        .write_all("Request too big".as_bytes())?;
+                                    return Ok(());
+                                }
 
-                        let app_state = Server::get_server_data(&data_channels);
-                        info!("assembled state: {:?}", app_state);
+                                let mut buf = vec![0; len];
+                                req.read_exact(&mut buf)?;
+
+                                let stops =
+                                    match serde_json::from_slice::<Vec<ColorStopUpdate>>(&buf) {
+                                        Ok(stops) => stops,
+                                        Err(e) => {
+                                            info!("Error parsing color map: {}", e);
+                                            req.into_status_response(400)?
+                                                .write_all("JSON error".as_bytes())?;
+                                            return Ok(());
+                                        }
+                                    };
+
+                                // Bare levels are in whatever unit the user has picked
+                                let unit = match &unit_channel {
+                                    Some(channel) => {
+                                        Server::get_server_data(std::slice::from_ref(channel))
+                                            .glucose_unit
+                                            .unwrap_or_default()
+                                    }
+                                    None => GlucoseUnit::default(),
+                                };
+                                let stops: Vec<ColorStop> =
+                                    stops.iter().map(|stop| stop.resolve(unit)).collect();
+
+                                if let Err(e) = validate(&stops) {
+                                    info!("Rejecting color map: {}", e);
+                                    req.into_status_response(400)?
+                                        .write_all(e.to_string().as_bytes())?;
+                                    return Ok(());
+                                }
 
-                        // Serialize, send back to web app
-                        let state_ser = serde_json::to_string(&app_state).unwrap();
-                        req.into_ok_response()?
-                            .write_all(state_ser.as_bytes())
-                            .map(|_| ())
-                    },
-                )?;
-            }
+                                let update = ServerUpdate {
+                                    color_map: Some(stops),
+                                    ..Default::default()
+                                };
+                                Server::send_server_update(&data_channels, &update);
+                                req.into_ok_response()?
+                                    .write_all("New color map applied".as_bytes())?;
+
+                                Ok(())
+                            },
+                        )?;
+                }
 
-            // Listener: Handle new settings from the web app
-            {
-                let data_channels = self.data_channels.clone();
-                self.server
-                    .as_mut()
-                    .unwrap()
-                    .fn_handler::<anyhow::Error, _>(
-                        &format!("/api/{}/{}", API_VER, API_RESET),
-                        Method::Post,
+                // Listener: Serve the device's status when on request
+                {
+                    let data_channels = self.data_channels.clone();
+                    self.server.as_mut().unwrap().fn_handler(
+                        &format!("/api/{}/{}", API_VER, API_STATE),
+                        Method::Get,
                         move |req| {
-                            let mut resp = req.into_ok_response()?;
+                            info!("Get request on /state!");
 
-                            Server::send_reset_signal(&data_channels);
-                            info!("Resetting");
-                            write!(resp, "All settings reset")?;
+                            let app_state = Server::get_server_data(&data_channels);
+                            info!("assembled state: {:?}", app_state);
 
-                            Ok(())
+                            // Serialize, send back to web app
+                            let state_ser = serde_json::to_string(&app_state).unwrap();
+                            req.into_ok_response()?
+                                .write_all(state_ser.as_bytes())
+                                .map(|_| ())
                         },
                     )?;
-            }
+                }
 
-            Ok(())
-        }
+                // Listener: Handle new settings from the web app
+                {
+                    let data_channels = self.data_channels.clone();
+                    self.server
+                        .as_mut()
+                        .unwrap()
+                        .fn_handler::<anyhow::Error, _>(
+                            &format!("/api/{}/{}", API_VER, API_RESET),
+                            Method::Post,
+                            move |req| {
+                                let mut resp = req.into_ok_response()?;
+
+                                Server::send_reset_signal(&data_channels);
+                                info!("Resetting");
+                                write!(resp, "All settings reset")?;
+
+                                Ok(())
+                            },
+                        )?;
+                }
 
-        pub fn send_server_update(channels: &Vec<Sender<ServableDataReq>>, update: &ServerUpdate) {
-            for channel in channels.iter() {
-                channel
-                    .send(ServableDataReq::Set((*update).to_owned()))
-                    .unwrap();
+                Ok(())
             }
-        }
 
-        pub fn get_server_data(channels: &Vec<Sender<ServableDataReq>>) -> ServerData {
-            let mut num_tx = 0;
-            let (tx, rx) = mpsc::channel::<ServableDataRsp>();
-            for channel in channels.iter() {
-                channel.send(ServableDataReq::Get(tx.clone())).unwrap();
-                num_tx += 1;
+            pub fn send_server_update(
+                channels: &Vec<Sender<ServableDataReq>>,
+                update: &ServerUpdate,
+            ) {
+                for channel in channels.iter() {
+                    channel
+                        .send(ServableDataReq::Set((*update).to_owned()))
+                        .unwrap();
+                }
             }
 
-            let mut server_data = ServerData::new();
+            pub fn get_server_data(channels: &[Sender<ServableDataReq>]) -> ServerData {
+                let mut num_tx = 0;
+                let (tx, rx) = mpsc::channel::<ServableDataRsp>();
+                for channel in channels.iter() {
+                    channel.send(ServableDataReq::Get(tx.clone())).unwrap();
+                    num_tx += 1;
+                }
 
-            let mut num_rx = 0;
-            while num_rx < num_tx {
-                if let Ok(rsp) = rx.recv() {
-                    if let ServableDataRsp::Data(serve_data) = rsp {
-                        server_data.merge(&serve_data);
+                let mut server_data = ServerData::new();
+
+                let mut num_rx = 0;
+                while num_rx < num_tx {
+                    if let Ok(rsp) = rx.recv() {
+                        if let ServableDataRsp::Data(serve_data) = rsp {
+                            server_data.merge(&serve_data);
+                        }
+                        // Whether the data is present or not, we got a repsonse, so increment our
+                        // count
+                        num_rx += 1;
                     }
-                    // Whether the data is present or not, we got a repsonse, so increment our
-                    // count
-                    num_rx += 1;
+                    // TODO: Some kind of timeout or check for no response
                 }
-                // TODO: Some kind of timeout or check for no response
-            }
 
-            server_data
-        }
+                server_data
+            }
 
-        pub fn send_reset_signal(channels: &Vec<Sender<ServableDataReq>>) {
-            for channel in channels.iter() {
-                channel.send(ServableDataReq::Reset).unwrap();
+            pub fn send_reset_signal(channels: &Vec<Sender<ServableDataReq>>) {
+                for channel in channels.iter() {
+                    channel.send(ServableDataReq::Reset).unwrap();
+                }
             }
-        }
 
-        pub fn stop(&mut self) {
-            self.server = None
+            pub fn stop(&mut self) {
+                self.server = None
+            }
         }
     }
-
-    pub trait ServableData {
-        fn get_channel(&mut self) -> Sender<ServableDataReq>;
-        fn handle_server_req(&mut self);
-    }
 }
@@ -0,0 +1,240 @@
+pub mod share {
+    use crate::glucose::glucose::{GlucoseReading, GlucoseTrend};
+    use serde::Deserialize;
+
+    // A single entry of ReadPublisherLatestGlucoseValues, e.g.
+    // {"WT":"Date(1700000000000)","ST":"Date(1700000000000)",
+    //  "DT":"Date(1700000000000-0500)","Value":123,"Trend":"Flat"}
+    #[derive(Deserialize, Debug)]
+    pub struct ShareReading {
+        #[serde(rename = "WT")]
+        wt: String,
+        #[serde(rename = "ST")]
+        st: Option<String>,
+        #[serde(rename = "DT")]
+        dt: Option<String>,
+        #[serde(rename = "Value")]
+        value: isize,
+        #[serde(rename = "Trend")]
+        trend: ShareTrend,
+    }
+
+    // Older Share servers send the trend as an index, newer ones as its name
+    #[derive(Deserialize, Debug)]
+    #[serde(untagged)]
+    enum ShareTrend {
+        Index(u8),
+        Name(String),
+    }
+
+    impl ShareTrend {
+        fn to_trend(&self) -> GlucoseTrend {
+            match self {
+                Self::Index(idx) => GlucoseTrend::from_index(*idx),
+                Self::Name(name) => GlucoseTrend::from_name(name),
+            }
+        }
+    }
+
+    #[derive(Debug, Copy, Clone, PartialEq)]
+    pub struct ShareDate {
+        // Milliseconds since the Unix epoch
+        pub millis: i64,
+        // Offset from UTC in minutes, if the date carried one
+        pub offset: Option<i16>,
+    }
+
+    // Parse a .NET JSON date, "/Date(1700000000000)/" or "Date(1700000000000-0500)"
+    pub fn parse_date(date: &str) -> anyhow::Result<ShareDate> {
+        let start = date
+            .find('(')
+            .ok_or_else(|| anyhow::anyhow!("No opening parenthesis in {:?}", date))?;
+        let end = date
+            .rfind(')')
+            .ok_or_else(|| anyhow::anyhow!("No closing parenthesis in {:?}", date))?;
+        if end <= start {
+            anyhow::bail!("Parentheses out of order in {:?}", date);
+        }
+
+        let inner = &date[start + 1..end];
+
+        // The sign of the offset is the first +/- after any leading minus on the timestamp
+        let split = inner
+            .char_indices()
+            .skip(1)
+            .find(|(_, c)| *c == '+' || *c == '-')
+            .map(|(idx, _)| idx);
+
+        let (millis, offset) = match split {
+            Some(idx) => (&inner[..idx], Some(parse_offset(&inner[idx..])?)),
+            None => (inner, None),
+        };
+
+        let millis = millis
+            .parse::<i64>()
+            .map_err(|e| anyhow::anyhow!("Bad timestamp in {:?}: {}", date, e))?;
+
+        Ok(ShareDate { millis, offset })
+    }
+
+    // "+hhmm" or "-hhmm" to minutes
+    fn parse_offset(offset: &str) -> anyhow::Result<i16> {
+        let (sign, digits) = offset.split_at(1);
+        if digits.len() != 4 || !digits.chars().all(|c| c.is_ascii_digit()) {
+            anyhow::bail!("Bad timezone offset {:?}", offset);
+        }
+
+        let hours: i16 = digits[..2].parse()?;
+        let minutes: i16 = digits[2..].parse()?;
+        let total = hours * 60 + minutes;
+
+        Ok(if sign == "-" { -total } else { total })
+    }
+
+    // Convert the entries of a glucose readings response
+    pub fn to_readings(readings: Vec<ShareReading>) -> anyhow::Result<Vec<GlucoseReading>> {
+        readings
+            .into_iter()
+            .map(|reading| {
+                let time = parse_date(&reading.wt)?.millis;
+                let system_offset = match &reading.st {
+                    Some(st) => parse_date(st)?.offset,
+                    None => None,
+                };
+                let display_offset = match &reading.dt {
+                    Some(dt) => parse_date(dt)?.offset,
+                    None => None,
+                };
+
+                Ok(GlucoseReading {
+                    time,
+                    value: reading.value,
+                    trend: reading.trend.to_trend(),
+                    system_offset,
+                    display_offset,
+                })
+            })
+            .collect()
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        // As current Share servers send them, trends by name
+        const READINGS: &str = include_str!("../tests/fixtures/share_readings.json");
+        // As older Share servers send them, trends by index and dates in slashes
+        const READINGS_INDEXED: &str =
+            include_str!("../tests/fixtures/share_readings_indexed.json");
+
+        fn parse_readings(body: &str) -> anyhow::Result<Vec<GlucoseReading>> {
+            to_readings(serde_json::from_str(body)?)
+        }
+
+        #[test]
+        fn deserializes_trend_names() {
+            let readings: Vec<ShareReading> = serde_json::from_str(READINGS).unwrap();
+
+            assert_eq!(readings.len(), 3);
+            assert_eq!(readings[0].wt, "Date(1700000600000)");
+            assert_eq!(readings[0].dt.as_deref(), Some("Date(1700000600000-0500)"));
+            assert_eq!(readings[0].value, 142);
+            assert!(matches!(&readings[0].trend, ShareTrend::Name(name) if name == "FortyFiveUp"));
+        }
+
+        #[test]
+        fn deserializes_trend_indexes() {
+            let readings: Vec<ShareReading> = serde_json::from_str(READINGS_INDEXED).unwrap();
+
+            assert_eq!(readings.len(), 2);
+            assert_eq!(readings[0].value, 88);
+            assert!(matches!(readings[0].trend, ShareTrend::Index(6)));
+        }
+
+        #[test]
+        fn reading_without_local_dates() {
+            let readings: Vec<ShareReading> = serde_json::from_str(
+                r#"[{"WT":"Date(1700000000000)","Value":100,"Trend":"Flat"}]"#,
+            )
+            .unwrap();
+
+            assert!(readings[0].st.is_none());
+            assert!(readings[0].dt.is_none());
+        }
+
+        #[test]
+        fn parses_readings_with_trend_names() {
+            let readings = parse_readings(READINGS).unwrap();
+
+            assert_eq!(readings.len(), 3);
+            assert_eq!(readings[0].time, 1700000600000);
+            assert_eq!(readings[0].value, 142);
+            assert!(matches!(readings[0].trend, GlucoseTrend::FortyFiveUp));
+            assert_eq!(readings[0].system_offset, None);
+            assert_eq!(readings[0].display_offset, Some(-300));
+            assert!(matches!(readings[1].trend, GlucoseTrend::Flat));
+            assert!(matches!(readings[2].trend, GlucoseTrend::NotComputable));
+        }
+
+        #[test]
+        fn parses_readings_with_trend_indexes() {
+            let readings = parse_readings(READINGS_INDEXED).unwrap();
+
+            assert_eq!(readings.len(), 2);
+            assert_eq!(readings[0].time, 1700000600000);
+            assert!(matches!(readings[0].trend, GlucoseTrend::SingleDown));
+            assert_eq!(readings[0].display_offset, Some(60));
+            assert!(matches!(readings[1].trend, GlucoseTrend::Flat));
+        }
+
+        #[test]
+        fn rejects_malformed_readings() {
+            assert!(parse_readings(r#"[{"WT":"Date(1700000000000)"}]"#).is_err());
+            assert!(parse_readings(r#"[{"WT":"never","Value":100,"Trend":"Flat"}]"#).is_err());
+        }
+
+        #[test]
+        fn parses_dates_without_offset() {
+            let expected = ShareDate {
+                millis: 1700000000000,
+                offset: None,
+            };
+
+            assert_eq!(parse_date("Date(1700000000000)").unwrap(), expected);
+            assert_eq!(parse_date("/Date(1700000000000)/").unwrap(), expected);
+        }
+
+        #[test]
+        fn parses_date_offsets() {
+            let east = parse_date("/Date(1700000000000+0530)/").unwrap();
+            assert_eq!(east.millis, 1700000000000);
+            assert_eq!(east.offset, Some(330));
+
+            let west = parse_date("Date(1700000000000-0500)").unwrap();
+            assert_eq!(west.millis, 1700000000000);
+            assert_eq!(west.offset, Some(-300));
+
+            let utc = parse_date("Date(1700000000000+0000)").unwrap();
+            assert_eq!(utc.offset, Some(0));
+        }
+
+        #[test]
+        fn parses_dates_before_the_epoch() {
+            let date = parse_date("Date(-86400000-0100)").unwrap();
+
+            assert_eq!(date.millis, -86400000);
+            assert_eq!(date.offset, Some(-60));
+            assert_eq!(parse_date("Date(-86400000)").unwrap().offset, None);
+        }
+
+        #[test]
+        fn rejects_malformed_dates() {
+            assert!(parse_date("1700000000000").is_err());
+            assert!(parse_date("Date(1700000000000").is_err());
+            assert!(parse_date(")Date(").is_err());
+            assert!(parse_date("Date(1700000000000+05)").is_err());
+            assert!(parse_date("Date(1700000000000+05:30)").is_err());
+            assert!(parse_date("Date(soon)").is_err());
+        }
+    }
+}
@@ -0,0 +1,357 @@
+pub mod stale {
+    use crate::colormap::colormap::ColorMap;
+    use crate::glucose::glucose::GlucoseReading;
+    use crate::lamp::lamp::LedState;
+    use crate::server::server::{ServableData, ServableDataReq, ServableDataRsp, ServerData};
+    use crate::status::status::{LOST_SIGNATURE, STALE_SIGNATURE};
+    use crate::storage::storage::Storable;
+    use crate::sys::sys::uptime;
+    use crate::time::time::wall_clock_ms;
+    use crate::trend::trend::TrendMap;
+    use log::info;
+    use serde::{Deserialize, Serialize};
+    use std::sync::mpsc;
+
+    // How far behind the latest reading is
+    #[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq)]
+    #[serde(rename_all = "lowercase")]
+    pub enum ReadingStage {
+        Fresh,
+        Aging,
+        Stale,
+        Lost,
+    }
+
+    // Reading ages, in minutes, at which each stage starts
+    #[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq)]
+    pub struct StaleThresholds {
+        pub aging: u16,
+        pub stale: u16,
+        pub lost: u16,
+    }
+
+    impl StaleThresholds {
+        // Each stage has to start after the one before it
+        pub fn is_valid(&self) -> bool {
+            0 < self.aging && self.aging < self.stale && self.stale < self.lost
+        }
+
+        pub fn stage(&self, age: u64) -> ReadingStage {
+            let minutes = age / 60;
+
+            if minutes >= self.lost as u64 {
+                ReadingStage::Lost
+            } else if minutes >= self.stale as u64 {
+                ReadingStage::Stale
+            } else if minutes >= self.aging as u64 {
+                ReadingStage::Aging
+            } else {
+                ReadingStage::Fresh
+            }
+        }
+    }
+
+    impl Default for StaleThresholds {
+        fn default() -> Self {
+            StaleThresholds {
+                aging: 15,
+                stale: 30,
+                lost: 60,
+            }
+        }
+    }
+
+    // Tracks how old the latest reading is, so the lamp never keeps showing a
+    // color for a sensor that stopped reporting
+    pub struct StaleMonitor {
+        thresholds: StaleThresholds,
+        latest: Option<GlucoseReading>,
+        // Local time the latest reading came in, and how old it was then. Used
+        // to age the reading when the wall clock isn't set.
+        received_at: u64,
+        age_at_receipt: u64,
+        server_channel: Option<mpsc::Receiver<ServableDataReq>>,
+        save_data: bool,
+    }
+
+    impl Default for StaleMonitor {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl StaleMonitor {
+        pub fn new() -> Self {
+            StaleMonitor {
+                thresholds: StaleThresholds::default(),
+                latest: None,
+                received_at: 0,
+                age_at_receipt: 0,
+                server_channel: None,
+                save_data: false,
+            }
+        }
+
+        // `wall_now` is the current time in ms since the Unix epoch, if the clock is set
+        pub fn reading_received(
+            &mut self,
+            now: u64,
+            wall_now: Option<i64>,
+            reading: &GlucoseReading,
+        ) {
+            if let Some(latest) = &self.latest {
+                if latest.time >= reading.time {
+                    return;
+                }
+            }
+
+            self.latest = Some(*reading);
+            self.received_at = now;
+            self.age_at_receipt = wall_now
+                .map(|wall_now| ((wall_now - reading.time) / 1000).max(0) as u64)
+                .unwrap_or(0);
+        }
+
+        // Forget the latest reading, e.g. after switching sources
+        pub fn clear(&mut self) {
+            self.latest = None;
+            self.received_at = 0;
+            self.age_at_receipt = 0;
+        }
+
+        // Seconds since the latest reading was taken
+        pub fn age(&self, now: u64, wall_now: Option<i64>) -> Option<u64> {
+            let latest = self.latest.as_ref()?;
+
+            Some(match wall_now {
+                Some(wall_now) => ((wall_now - latest.time) / 1000).max(0) as u64,
+                None => self.age_at_receipt + now.saturating_sub(self.received_at),
+            })
+        }
+
+        pub fn stage(&self, now: u64, wall_now: Option<i64>) -> Option<ReadingStage> {
+            self.age(now, wall_now)
+                .map(|age| self.thresholds.stage(age))
+        }
+
+        // What the lamp should show for the latest reading at its current age
+        pub fn led_state(
+            &self,
+            now: u64,
+            wall_now: Option<i64>,
+            color_map: &ColorMap,
+            trend_map: &TrendMap,
+        ) -> Option<LedState> {
+            let latest = self.latest.as_ref()?;
+
+            // An old trend says little about where glucose is now, so only fresh
+            // readings show one
+            Some(match self.stage(now, wall_now)? {
+                ReadingStage::Fresh => trend_map.led_state(color_map, latest),
+                ReadingStage::Aging => match color_map.led_state(latest.value) {
+                    LedState::Steady(color) | LedState::Breathe(color) => LedState::Faded(color),
+                    state => state,
+                },
+                ReadingStage::Stale => STALE_SIGNATURE,
+                ReadingStage::Lost => LOST_SIGNATURE,
+            })
+        }
+
+        pub fn need_to_save(&self) -> bool {
+            self.save_data
+        }
+
+        pub fn saved(&mut self) {
+            self.save_data = false;
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct NvsStaleState {
+        thresholds: StaleThresholds,
+    }
+
+    impl Storable for StaleMonitor {
+        fn store_tag(&self) -> &str {
+            "stale"
+        }
+
+        fn store_data(&self) -> Vec<u8> {
+            let data = NvsStaleState {
+                thresholds: self.thresholds,
+            };
+
+            serde_json::to_string(&data).unwrap().into_bytes()
+        }
+
+        fn recall_data(&mut self, data: &[u8]) {
+            let nvs_state = serde_json::from_slice::<NvsStaleState>(data).unwrap();
+            self.thresholds = nvs_state.thresholds;
+            self.save_data = false;
+        }
+    }
+
+    impl ServableData for StaleMonitor {
+        fn get_channel(&mut self) -> mpsc::Sender<ServableDataReq> {
+            let (tx, rx) = mpsc::channel::<ServableDataReq>();
+            self.server_channel = Some(rx);
+            tx
+        }
+
+        fn handle_server_req(&mut self) {
+            if let Some(channel) = &self.server_channel {
+                if let Ok(req) = channel.try_recv() {
+                    info!("stale monitor got a request from server");
+
+                    if let ServableDataReq::Get(back_channel) = &req {
+                        info!("Sending stale state to server");
+                        let now = uptime();
+                        let wall_now = wall_clock_ms();
+                        let mut rsp = ServerData::new();
+                        rsp.reading_age = self.age(now, wall_now);
+                        rsp.reading_stage = self.stage(now, wall_now);
+                        rsp.stale_thresholds = Some(self.thresholds);
+                        back_channel.send(ServableDataRsp::Data(rsp)).unwrap();
+                    }
+
+                    if let ServableDataReq::Set(update) = &req {
+                        if let Some(thresholds) = &update.stale_thresholds {
+                            if thresholds.is_valid() {
+                                self.thresholds = *thresholds;
+                                self.save_data = true;
+                            } else {
+                                info!("Ignoring out of order stale thresholds {:?}", thresholds);
+                            }
+                        }
+                    }
+
+                    if let ServableDataReq::Reset = &req {
+                        self.thresholds = StaleThresholds::default();
+                        self.save_data = true;
+                    }
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::status::status::LampStatus;
+
+        #[test]
+        fn old_readings_show_their_own_signatures() {
+            let (color_map, trend_map) = (ColorMap::new(), TrendMap::new());
+            let mut monitor = StaleMonitor::new();
+            let reading = GlucoseReading {
+                value: 100,
+                ..GlucoseReading::new()
+            };
+            monitor.reading_received(0, None, &reading);
+            let at = |minutes: u64| monitor.led_state(minutes * 60, None, &color_map, &trend_map);
+
+            assert_eq!(at(30), Some(STALE_SIGNATURE));
+            assert_eq!(at(60), Some(LOST_SIGNATURE));
+            // Nothing to confuse with the lamp starting up
+            assert_ne!(at(30), LampStatus::Booting.led_state());
+            assert_ne!(at(0), Some(STALE_SIGNATURE));
+        }
+
+        #[test]
+        fn stages_start_on_their_thresholds() {
+            let thresholds = StaleThresholds::default();
+            let stage = |minutes: u64, extra: u64| thresholds.stage(minutes * 60 + extra);
+
+            assert_eq!(stage(0, 0), ReadingStage::Fresh);
+            assert_eq!(stage(14, 59), ReadingStage::Fresh);
+            assert_eq!(stage(15, 0), ReadingStage::Aging);
+            assert_eq!(stage(29, 59), ReadingStage::Aging);
+            assert_eq!(stage(30, 0), ReadingStage::Stale);
+            assert_eq!(stage(59, 59), ReadingStage::Stale);
+            assert_eq!(stage(60, 0), ReadingStage::Lost);
+            assert_eq!(stage(24 * 60, 0), ReadingStage::Lost);
+        }
+
+        #[test]
+        fn thresholds_have_to_be_in_order() {
+            let thresholds = |aging, stale, lost| StaleThresholds { aging, stale, lost };
+
+            assert!(StaleThresholds::default().is_valid());
+            assert!(thresholds(1, 2, 3).is_valid());
+            assert!(!thresholds(0, 2, 3).is_valid());
+            assert!(!thresholds(2, 2, 3).is_valid());
+            assert!(!thresholds(1, 3, 3).is_valid());
+            assert!(!thresholds(30, 15, 60).is_valid());
+        }
+
+        #[test]
+        fn ages_with_uptime_without_a_clock() {
+            let mut monitor = StaleMonitor::new();
+            assert_eq!(monitor.age(100, None), None);
+
+            let reading = GlucoseReading {
+                time: 1_700_000_000_000,
+                ..GlucoseReading::new()
+            };
+            // Already two minutes old when it came in, while the clock was set
+            monitor.reading_received(100, Some(reading.time + 120_000), &reading);
+
+            assert_eq!(monitor.age(100, None), Some(120));
+            assert_eq!(monitor.age(400, None), Some(420));
+            // A clock that's since been set wins
+            assert_eq!(monitor.age(400, Some(reading.time + 60_000)), Some(60));
+
+            // Without a clock at all, the reading counts as new when it arrives
+            let newer = GlucoseReading {
+                time: reading.time + 300_000,
+                ..reading
+            };
+            monitor.reading_received(500, None, &newer);
+            assert_eq!(monitor.age(500, None), Some(0));
+            assert_eq!(
+                monitor.stage(500 + 15 * 60, None),
+                Some(ReadingStage::Aging)
+            );
+        }
+
+        #[test]
+        fn older_readings_are_ignored() {
+            let mut monitor = StaleMonitor::new();
+            let reading = GlucoseReading {
+                time: 1_700_000_000_000,
+                ..GlucoseReading::new()
+            };
+            monitor.reading_received(100, None, &reading);
+            let older = GlucoseReading {
+                time: reading.time - 300_000,
+                ..reading
+            };
+            monitor.reading_received(200, None, &older);
+
+            assert_eq!(monitor.age(200, None), Some(100));
+        }
+
+        #[test]
+        fn clearing_forgets_the_reading() {
+            let (color_map, trend_map) = (ColorMap::new(), TrendMap::new());
+            let mut monitor = StaleMonitor::new();
+            let reading = GlucoseReading {
+                time: 1_700_000_000_000,
+                ..GlucoseReading::new()
+            };
+            monitor.reading_received(0, None, &reading);
+            monitor.clear();
+
+            assert_eq!(monitor.age(0, None), None);
+            assert_eq!(monitor.led_state(0, None, &color_map, &trend_map), None);
+
+            // Even an older reading from the new source is taken
+            let older = GlucoseReading {
+                time: reading.time - 300_000,
+                ..reading
+            };
+            monitor.reading_received(10, None, &older);
+            assert_eq!(monitor.age(10, None), Some(0));
+        }
+    }
+}
@@ -0,0 +1,151 @@
+pub mod status {
+    use crate::lamp::lamp::{Lamp, LedState, CYAN, ORANGE, WHITE, YELLOW};
+    use crate::server::server::{ServableData, ServableDataReq, ServableDataRsp, ServerData};
+    use log::info;
+    use rgb_led::SmartLed;
+    use serde::{Deserialize, Serialize};
+    use std::sync::mpsc;
+
+    // What the lamp is doing, as far as the user is concerned
+    #[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq)]
+    #[serde(rename_all = "kebab-case")]
+    pub enum LampStatus {
+        Booting,
+        // Waiting for settings from the web interface
+        ApMode,
+        // In AP mode because the stored network couldn't be joined
+        WifiFailed,
+        WifiConnecting,
+        // Logging in to the glucose source
+        SourceConnecting,
+        // The glucose source refused the login, retrying now and then
+        AuthFailed,
+        // Logged in, but no reading has come in yet
+        NoReadings,
+        // The latest reading is too old to trust, see StaleMonitor
+        Stale,
+        // Showing glucose
+        Ok,
+    }
+
+    // What the stale monitor shows in place of readings that are too old, see
+    // ReadingStage. Listed here so every signature the lamp has is in one place.
+    pub const STALE_SIGNATURE: LedState = LedState::Blink(WHITE);
+    pub const LOST_SIGNATURE: LedState = LedState::Breathe(WHITE);
+
+    impl LampStatus {
+        // Statuses the lamp shows a signature for. Glucose and stale readings are
+        // shown by the color map and stale monitor instead, stale ones with the
+        // signatures above.
+        //
+        // Connecting blocks the main loop, so those signatures are steady rather
+        // than animations that would freeze part way through
+        pub fn led_state(self) -> Option<LedState> {
+            match self {
+                LampStatus::Booting => Some(LedState::Steady(WHITE)),
+                LampStatus::ApMode => Some(LedState::Breathe(YELLOW)),
+                LampStatus::WifiFailed => Some(LedState::Blink(YELLOW)),
+                LampStatus::WifiConnecting => Some(LedState::Steady(YELLOW)),
+                LampStatus::SourceConnecting => Some(LedState::Steady(CYAN)),
+                LampStatus::AuthFailed => Some(LedState::Blink(ORANGE)),
+                LampStatus::NoReadings => Some(LedState::Breathe(CYAN)),
+                LampStatus::Stale | LampStatus::Ok => None,
+            }
+        }
+    }
+
+    // Puts the app's status on the lamp, and reports it to the server
+    pub struct StatusIndicator {
+        // Nothing until the first call to show()
+        status: Option<LampStatus>,
+        server_channel: Option<mpsc::Receiver<ServableDataReq>>,
+    }
+
+    impl Default for StatusIndicator {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl StatusIndicator {
+        pub fn new() -> Self {
+            StatusIndicator {
+                status: None,
+                server_channel: None,
+            }
+        }
+
+        pub fn status(&self) -> Option<LampStatus> {
+            self.status
+        }
+
+        // Only touches the lamp when the status changes, so it's fine to call
+        // this every time round the loop
+        pub fn show<L: SmartLed>(&mut self, status: LampStatus, lamp: &mut Lamp<L>) {
+            if Some(status) == self.status {
+                return;
+            }
+
+            info!("Status now {:?}", status);
+            self.status = Some(status);
+            if let Some(led_state) = status.led_state() {
+                lamp.set_color(led_state);
+            }
+        }
+    }
+
+    impl ServableData for StatusIndicator {
+        fn get_channel(&mut self) -> mpsc::Sender<ServableDataReq> {
+            let (tx, rx) = mpsc::channel::<ServableDataReq>();
+            self.server_channel = Some(rx);
+            tx
+        }
+
+        fn handle_server_req(&mut self) {
+            if let Some(channel) = &self.server_channel {
+                if let Ok(req) = channel.try_recv() {
+                    info!("status indicator got a request from server");
+
+                    if let ServableDataReq::Get(back_channel) = &req {
+                        info!("Sending status to server");
+                        let mut rsp = ServerData::new();
+                        rsp.status = self.status;
+                        back_channel.send(ServableDataRsp::Data(rsp)).unwrap();
+                    }
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const ALL: [LampStatus; 9] = [
+            LampStatus::Booting,
+            LampStatus::ApMode,
+            LampStatus::WifiFailed,
+            LampStatus::WifiConnecting,
+            LampStatus::SourceConnecting,
+            LampStatus::AuthFailed,
+            LampStatus::NoReadings,
+            LampStatus::Stale,
+            LampStatus::Ok,
+        ];
+
+        #[test]
+        fn every_signature_is_distinct() {
+            let mut signatures: Vec<LedState> =
+                ALL.iter().filter_map(|status| status.led_state()).collect();
+            signatures.extend([STALE_SIGNATURE, LOST_SIGNATURE]);
+
+            for (idx, signature) in signatures.iter().enumerate() {
+                assert!(
+                    !signatures[idx + 1..].contains(signature),
+                    "{:?} is used twice",
+                    signature
+                );
+            }
+        }
+    }
+}
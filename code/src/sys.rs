@@ -1,68 +1,89 @@
 pub mod sys {
-    use crate::server::server::{ServableData, ServableDataReq, ServableDataRsp, ServerData};
-    use esp_idf_hal::temp_sensor::*;
-    use esp_idf_svc::hal::{gpio::Gpio5, gpio::Output, gpio::PinDriver};
-    use log::info;
-    use std::sync::mpsc;
-    use std::time::{SystemTime, UNIX_EPOCH};
+    #[cfg(not(target_os = "espidf"))]
+    use std::{sync::OnceLock, time::Instant};
 
+    // Seconds since boot. Unlike the wall clock, this never jumps.
     pub fn uptime() -> u64 {
-        SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("Time went backwards")
-            .as_secs()
+        uptime_ms() / 1_000
     }
 
-    pub struct Sys<'a> {
-        indicator: PinDriver<'a, Gpio5, Output>,
-        temp: TempSensorDriver<'a>,
-        server_channel: Option<mpsc::Receiver<ServableDataReq>>,
+    // Milliseconds since boot, for anything that needs finer timing than uptime()
+    #[cfg(target_os = "espidf")]
+    pub fn uptime_ms() -> u64 {
+        (unsafe { esp_idf_svc::sys::esp_timer_get_time() } / 1_000) as u64
     }
 
-    impl<'a> Sys<'a> {
-        pub fn new(indicator: PinDriver<'a, Gpio5, Output>, temp_sensor: TempSensor) -> Self {
-            let cfg = TempSensorConfig::default();
-            let mut temp = TempSensorDriver::new(&cfg, temp_sensor).unwrap();
-            temp.enable().unwrap();
+    // Off the device, time since this was first asked for
+    #[cfg(not(target_os = "espidf"))]
+    pub fn uptime_ms() -> u64 {
+        static STARTED: OnceLock<Instant> = OnceLock::new();
+        STARTED.get_or_init(Instant::now).elapsed().as_millis() as u64
+    }
 
-            Sys {
-                indicator,
-                temp,
-                server_channel: None,
-            }
-        }
+    #[cfg(target_os = "espidf")]
+    pub use esp::Sys;
 
-        pub fn ind_on(&mut self) {
-            self.indicator.set_high().unwrap();
-        }
+    // The board's indicator LED and temperature sensor
+    #[cfg(target_os = "espidf")]
+    mod esp {
+        use super::uptime;
+        use crate::server::server::{ServableData, ServableDataReq, ServableDataRsp, ServerData};
+        use esp_idf_hal::temp_sensor::*;
+        use esp_idf_svc::hal::{gpio::Gpio5, gpio::Output, gpio::PinDriver};
+        use log::info;
+        use std::sync::mpsc;
 
-        pub fn ind_off(&mut self) {
-            self.indicator.set_low().unwrap();
+        pub struct Sys<'a> {
+            indicator: PinDriver<'a, Gpio5, Output>,
+            temp: TempSensorDriver<'a>,
+            server_channel: Option<mpsc::Receiver<ServableDataReq>>,
         }
 
-        pub fn get_temp(&self) -> f32 {
-            self.temp.get_celsius().unwrap()
-        }
-    }
+        impl<'a> Sys<'a> {
+            pub fn new(indicator: PinDriver<'a, Gpio5, Output>, temp_sensor: TempSensor) -> Self {
+                let cfg = TempSensorConfig::default();
+                let mut temp = TempSensorDriver::new(&cfg, temp_sensor).unwrap();
+                temp.enable().unwrap();
+
+                Sys {
+                    indicator,
+                    temp,
+                    server_channel: None,
+                }
+            }
+
+            pub fn ind_on(&mut self) {
+                self.indicator.set_high().unwrap();
+            }
+
+            pub fn ind_off(&mut self) {
+                self.indicator.set_low().unwrap();
+            }
 
-    impl<'a> ServableData for Sys<'a> {
-        fn get_channel(&mut self) -> mpsc::Sender<ServableDataReq> {
-            let (tx, rx) = mpsc::channel::<ServableDataReq>();
-            self.server_channel = Some(rx);
-            tx
+            pub fn get_temp(&self) -> f32 {
+                self.temp.get_celsius().unwrap()
+            }
         }
 
-        fn handle_server_req(&mut self) {
-            if let Some(channel) = &self.server_channel {
-                if let Ok(req) = channel.try_recv() {
-                    info!("lamp got a request from server");
+        impl<'a> ServableData for Sys<'a> {
+            fn get_channel(&mut self) -> mpsc::Sender<ServableDataReq> {
+                let (tx, rx) = mpsc::channel::<ServableDataReq>();
+                self.server_channel = Some(rx);
+                tx
+            }
+
+            fn handle_server_req(&mut self) {
+                if let Some(channel) = &self.server_channel {
+                    if let Ok(req) = channel.try_recv() {
+                        info!("lamp got a request from server");
 
-                    if let ServableDataReq::Get(back_channel) = &req {
-                        info!("Sending lamp state to server");
-                        let mut rsp = ServerData::new();
-                        rsp.uptime = Some(uptime());
-                        rsp.temp = Some(self.get_temp());
-                        back_channel.send(ServableDataRsp::Data(rsp)).unwrap();
+                        if let ServableDataReq::Get(back_channel) = &req {
+                            info!("Sending lamp state to server");
+                            let mut rsp = ServerData::new();
+                            rsp.uptime = Some(uptime());
+                            rsp.temp = Some(self.get_temp());
+                            back_channel.send(ServableDataRsp::Data(rsp)).unwrap();
+                        }
                     }
                 }
             }
@@ -0,0 +1,335 @@
+pub mod time {
+    use serde::{Deserialize, Serialize};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    pub const DEFAULT_SERVERS: [&str; 2] = ["pool.ntp.org", "time.google.com"];
+
+    // Anything earlier means the clock was never set
+    const EARLIEST_VALID_TIME: u64 = 1_700_000_000;
+
+    // Milliseconds since the Unix epoch, if the clock has been set
+    pub fn wall_clock_ms() -> Option<i64> {
+        let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
+
+        if since_epoch.as_secs() < EARLIEST_VALID_TIME {
+            return None;
+        }
+
+        Some(since_epoch.as_millis() as i64)
+    }
+
+    // "Sun, 06 Nov 1994 08:49:37 GMT" to seconds since the Unix epoch
+    pub fn parse_http_date(date: &str) -> Option<i64> {
+        let mut parts = date.split_whitespace().skip(1);
+        let day: i64 = parts.next()?.parse().ok()?;
+        let month = match parts.next()? {
+            "Jan" => 1,
+            "Feb" => 2,
+            "Mar" => 3,
+            "Apr" => 4,
+            "May" => 5,
+            "Jun" => 6,
+            "Jul" => 7,
+            "Aug" => 8,
+            "Sep" => 9,
+            "Oct" => 10,
+            "Nov" => 11,
+            "Dec" => 12,
+            _ => return None,
+        };
+        let year: i64 = parts.next()?.parse().ok()?;
+        let time: Vec<i64> = parts
+            .next()?
+            .split(':')
+            .map(|field| field.parse().ok())
+            .collect::<Option<_>>()?;
+        let &[hour, minute, second] = time.as_slice() else {
+            return None;
+        };
+
+        Some(days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second)
+    }
+
+    // Days since 1970-01-01 of a proleptic Gregorian date
+    pub fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+        let year = if month <= 2 { year - 1 } else { year };
+        let era = (if year >= 0 { year } else { year - 399 }) / 400;
+        let year_of_era = year - era * 400;
+        let month_from_march = if month > 2 { month - 3 } else { month + 9 };
+        let day_of_year = (153 * month_from_march + 2) / 5 + day - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        era * 146097 + day_of_era - 719468
+    }
+
+    // Where the current wall-clock time came from
+    #[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq)]
+    #[serde(rename_all = "lowercase")]
+    pub enum ClockSource {
+        Unset,
+        Http,
+        Sntp,
+    }
+
+    #[cfg(target_os = "espidf")]
+    pub use esp::{max_servers, set_clock_from_http_date, TimeService};
+
+    // Setting the clock, which only the device can do
+    #[cfg(target_os = "espidf")]
+    mod esp {
+        use super::{parse_http_date, wall_clock_ms, ClockSource, DEFAULT_SERVERS};
+        use crate::server::server::{ServableData, ServableDataReq, ServableDataRsp, ServerData};
+        use crate::storage::storage::Storable;
+        use crate::sys::sys::uptime;
+        use esp_idf_svc::sntp::{EspSntp, SntpConf, SyncStatus};
+        use esp_idf_svc::sys::{settimeofday, timeval};
+        use log::info;
+        use serde::{Deserialize, Serialize};
+        use std::sync::mpsc;
+
+        // Set the clock from an HTTP Date header, unless it is already set. Good to a
+        // second or so, which is plenty until SNTP catches up.
+        pub fn set_clock_from_http_date(date: &str) {
+            if wall_clock_ms().is_some() {
+                return;
+            }
+
+            let Some(seconds) = parse_http_date(date) else {
+                info!("Couldn't parse HTTP date {:?}", date);
+                return;
+            };
+
+            let now = timeval {
+                tv_sec: seconds as _,
+                tv_usec: 0,
+            };
+            if unsafe { settimeofday(&now, std::ptr::null()) } == 0 {
+                info!("Clock set from HTTP date {:?}", date);
+            }
+        }
+
+        // How many servers SNTP can be given, set by CONFIG_LWIP_SNTP_MAX_SERVERS
+        pub fn max_servers() -> usize {
+            SntpConf::default().servers.len()
+        }
+
+        // Keeps the wall clock set via SNTP
+        pub struct TimeService {
+            sntp: Option<EspSntp<'static>>,
+            servers: Vec<String>,
+            // Uptime of the last completed SNTP sync
+            last_sync: Option<u64>,
+            server_channel: Option<mpsc::Receiver<ServableDataReq>>,
+            save_data: bool,
+        }
+
+        impl TimeService {
+            pub fn new() -> Self {
+                TimeService {
+                    sntp: None,
+                    servers: DEFAULT_SERVERS.iter().map(|s| s.to_string()).collect(),
+                    last_sync: None,
+                    server_channel: None,
+                    save_data: false,
+                }
+            }
+
+            // Start syncing, needs a network connection to get anywhere
+            pub fn start(&mut self) -> anyhow::Result<()> {
+                // Only one SNTP client can run at a time
+                self.sntp = None;
+
+                // Any slots we don't fill keep the esp-idf defaults as a fallback
+                let mut conf = SntpConf::default();
+                for (slot, server) in conf.servers.iter_mut().zip(self.servers.iter()) {
+                    *slot = server.as_str();
+                }
+                if self.servers.len() > conf.servers.len() {
+                    info!(
+                        "Only using the first {} NTP servers, see CONFIG_LWIP_SNTP_MAX_SERVERS",
+                        conf.servers.len()
+                    );
+                }
+
+                info!("Starting SNTP with {:?}", self.servers);
+                self.sntp = Some(EspSntp::new(&conf)?);
+                Ok(())
+            }
+
+            pub fn is_running(&self) -> bool {
+                self.sntp.is_some()
+            }
+
+            // Call regularly, the SNTP status only reads as completed once per sync
+            pub fn update(&mut self) {
+                if let Some(sntp) = &self.sntp {
+                    if sntp.get_sync_status() == SyncStatus::Completed {
+                        info!("SNTP sync completed");
+                        self.last_sync = Some(uptime());
+                    }
+                }
+            }
+
+            pub fn is_synced(&self) -> bool {
+                self.last_sync.is_some()
+            }
+
+            // Seconds since the last SNTP sync
+            pub fn sync_age(&self) -> Option<u64> {
+                self.last_sync
+                    .map(|last_sync| uptime().saturating_sub(last_sync))
+            }
+
+            pub fn clock_source(&self) -> ClockSource {
+                if self.is_synced() {
+                    ClockSource::Sntp
+                } else if wall_clock_ms().is_some() {
+                    ClockSource::Http
+                } else {
+                    ClockSource::Unset
+                }
+            }
+
+            pub fn need_to_save(&self) -> bool {
+                self.save_data
+            }
+
+            pub fn saved(&mut self) {
+                self.save_data = false;
+            }
+
+            fn set_servers(&mut self, servers: Vec<String>) {
+                self.servers = servers;
+                self.save_data = true;
+
+                // Pick up the new servers straight away if we're already syncing
+                if self.is_running() {
+                    self.start().unwrap_or_else(|error| {
+                        info!("Couldn't restart SNTP: {}", error);
+                    });
+                }
+            }
+        }
+
+        #[derive(Serialize, Deserialize)]
+        struct NvsTimeState {
+            servers: Vec<String>,
+        }
+
+        impl Storable for TimeService {
+            fn store_tag(&self) -> &str {
+                "time"
+            }
+
+            fn store_data(&self) -> Vec<u8> {
+                let data = NvsTimeState {
+                    servers: self.servers.clone(),
+                };
+
+                serde_json::to_string(&data).unwrap().into_bytes()
+            }
+
+            fn recall_data(&mut self, data: &[u8]) {
+                let nvs_state = serde_json::from_slice::<NvsTimeState>(data).unwrap();
+                self.servers = nvs_state.servers;
+                self.save_data = false;
+            }
+        }
+
+        impl ServableData for TimeService {
+            fn get_channel(&mut self) -> mpsc::Sender<ServableDataReq> {
+                let (tx, rx) = mpsc::channel::<ServableDataReq>();
+                self.server_channel = Some(rx);
+                tx
+            }
+
+            fn handle_server_req(&mut self) {
+                if let Some(channel) = &self.server_channel {
+                    if let Ok(req) = channel.try_recv() {
+                        info!("time got a request from server");
+
+                        if let ServableDataReq::Get(back_channel) = &req {
+                            info!("Sending time state to server");
+                            let mut rsp = ServerData::new();
+                            rsp.wall_time = wall_clock_ms();
+                            rsp.clock_source = Some(self.clock_source());
+                            rsp.time_sync_age = self.sync_age();
+                            rsp.ntp_servers = Some(self.servers.clone());
+                            back_channel.send(ServableDataRsp::Data(rsp)).unwrap();
+                        }
+
+                        if let ServableDataReq::Set(update) = &req {
+                            if let Some(servers) = &update.ntp_servers {
+                                if servers.is_empty() {
+                                    info!("Ignoring empty NTP server list");
+                                } else if servers.len() > max_servers() {
+                                    info!(
+                                        "Ignoring NTP server list, at most {} servers fit",
+                                        max_servers()
+                                    );
+                                } else {
+                                    self.set_servers(servers.clone());
+                                }
+                            }
+                        }
+
+                        if let ServableDataReq::Reset = &req {
+                            self.set_servers(
+                                DEFAULT_SERVERS.iter().map(|s| s.to_string()).collect(),
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn counts_days_from_the_epoch() {
+            assert_eq!(days_from_civil(1970, 1, 1), 0);
+            assert_eq!(days_from_civil(1969, 12, 31), -1);
+            assert_eq!(days_from_civil(2000, 2, 29), 11016);
+            assert_eq!(days_from_civil(2000, 3, 1), 11017);
+            assert_eq!(days_from_civil(2024, 2, 29), 19782);
+            // Not a leap year
+            assert_eq!(
+                days_from_civil(2100, 3, 1) - days_from_civil(2100, 2, 28),
+                1
+            );
+        }
+
+        #[test]
+        fn parses_http_dates() {
+            assert_eq!(parse_http_date("Thu, 01 Jan 1970 00:00:00 GMT"), Some(0));
+            // The example from RFC 7231
+            assert_eq!(
+                parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"),
+                Some(784111777)
+            );
+            assert_eq!(
+                parse_http_date("Thu, 29 Feb 2024 23:59:59 GMT"),
+                Some(1709251199)
+            );
+        }
+
+        #[test]
+        fn refuses_malformed_dates() {
+            for date in [
+                "",
+                "Sun,",
+                "Sun, 06 Nov 1994",
+                "Sun, xx Nov 1994 08:49:37 GMT",
+                "Sun, 06 November 1994 08:49:37 GMT",
+                "Sun, 06 Nov 94x 08:49:37 GMT",
+                "Sun, 06 Nov 1994 08:49 GMT",
+                "Sun, 06 Nov 1994 08:49:37:00 GMT",
+                "Sun, 06 Nov 1994 08:49:xx GMT",
+            ] {
+                assert_eq!(parse_http_date(date), None, "{}", date);
+            }
+        }
+    }
+}
@@ -0,0 +1,293 @@
+pub mod trend {
+    use crate::colormap::colormap::ColorMap;
+    use crate::glucose::glucose::{GlucoseReading, GlucoseTrend};
+    use crate::lamp::lamp::LedState;
+    use crate::server::server::{ServableData, ServableDataReq, ServableDataRsp, ServerData};
+    use crate::storage::storage::Storable;
+    use log::info;
+    use serde::{Deserialize, Serialize};
+    use std::sync::mpsc;
+
+    // How far ahead a Shift looks for its target color, per trend arrow
+    pub const SHIFT_STEP: isize = 20;
+
+    // How the lamp shows a trend on top of the color for the current value
+    #[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq)]
+    #[serde(tag = "style", rename_all = "lowercase")]
+    pub enum TrendStyle {
+        Steady,
+        // Breathe, with a shorter period for faster changes
+        Pulse { period: u32 },
+        // Swing towards the color glucose is heading for
+        Shift { period: u32 },
+    }
+
+    // Trends missing from stored settings keep their default style
+    #[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq)]
+    #[serde(default, rename_all = "kebab-case")]
+    pub struct TrendStyles {
+        pub double_up: TrendStyle,
+        pub single_up: TrendStyle,
+        pub forty_five_up: TrendStyle,
+        pub flat: TrendStyle,
+        pub forty_five_down: TrendStyle,
+        pub single_down: TrendStyle,
+        pub double_down: TrendStyle,
+    }
+
+    impl TrendStyles {
+        // Trends that can't be trusted always show steady
+        pub fn style(&self, trend: GlucoseTrend) -> TrendStyle {
+            match trend {
+                GlucoseTrend::DoubleUp => self.double_up,
+                GlucoseTrend::SingleUp => self.single_up,
+                GlucoseTrend::FortyFiveUp => self.forty_five_up,
+                GlucoseTrend::Flat => self.flat,
+                GlucoseTrend::FortyFiveDown => self.forty_five_down,
+                GlucoseTrend::SingleDown => self.single_down,
+                GlucoseTrend::DoubleDown => self.double_down,
+                GlucoseTrend::NoTrend
+                | GlucoseTrend::NotComputable
+                | GlucoseTrend::RateOutOfRange => TrendStyle::Steady,
+            }
+        }
+    }
+
+    // Styles as posted through the API, where trends left out keep the style they had
+    #[derive(Deserialize, Serialize, Debug, Default, Copy, Clone, PartialEq)]
+    #[serde(rename_all = "kebab-case")]
+    pub struct TrendStylesUpdate {
+        pub double_up: Option<TrendStyle>,
+        pub single_up: Option<TrendStyle>,
+        pub forty_five_up: Option<TrendStyle>,
+        pub flat: Option<TrendStyle>,
+        pub forty_five_down: Option<TrendStyle>,
+        pub single_down: Option<TrendStyle>,
+        pub double_down: Option<TrendStyle>,
+    }
+
+    impl TrendStylesUpdate {
+        pub fn merged_into(&self, styles: TrendStyles) -> TrendStyles {
+            TrendStyles {
+                double_up: self.double_up.unwrap_or(styles.double_up),
+                single_up: self.single_up.unwrap_or(styles.single_up),
+                forty_five_up: self.forty_five_up.unwrap_or(styles.forty_five_up),
+                flat: self.flat.unwrap_or(styles.flat),
+                forty_five_down: self.forty_five_down.unwrap_or(styles.forty_five_down),
+                single_down: self.single_down.unwrap_or(styles.single_down),
+                double_down: self.double_down.unwrap_or(styles.double_down),
+            }
+        }
+    }
+
+    impl Default for TrendStyles {
+        fn default() -> Self {
+            TrendStyles {
+                double_up: TrendStyle::Pulse { period: 1000 },
+                single_up: TrendStyle::Shift { period: 3000 },
+                forty_five_up: TrendStyle::Shift { period: 6000 },
+                flat: TrendStyle::Steady,
+                forty_five_down: TrendStyle::Shift { period: 6000 },
+                single_down: TrendStyle::Shift { period: 3000 },
+                double_down: TrendStyle::Pulse { period: 1000 },
+            }
+        }
+    }
+
+    // Stores how each trend is shown
+    pub struct TrendMap {
+        styles: TrendStyles,
+        server_channel: Option<mpsc::Receiver<ServableDataReq>>,
+        save_data: bool,
+    }
+
+    impl Default for TrendMap {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl TrendMap {
+        pub fn new() -> Self {
+            TrendMap {
+                styles: TrendStyles::default(),
+                server_channel: None,
+                save_data: false,
+            }
+        }
+
+        pub fn styles(&self) -> &TrendStyles {
+            &self.styles
+        }
+
+        // Color the reading through the color map, then layer its trend on top.
+        // Values the map already breathes for are alarms and are left alone.
+        pub fn led_state(&self, color_map: &ColorMap, reading: &GlucoseReading) -> LedState {
+            let LedState::Steady(color) = color_map.led_state(reading.value) else {
+                return color_map.led_state(reading.value);
+            };
+
+            match self.styles.style(reading.trend) {
+                TrendStyle::Steady => LedState::Steady(color),
+                TrendStyle::Pulse { period } => LedState::Pulse(color, period),
+                TrendStyle::Shift { period } => {
+                    let direction = reading.trend.direction().unwrap_or(0);
+                    let ahead = reading.value + direction * SHIFT_STEP;
+                    match color_map.led_state(ahead) {
+                        LedState::Steady(target) | LedState::Breathe(target) => {
+                            LedState::Shift(color, target, period)
+                        }
+                        _ => LedState::Steady(color),
+                    }
+                }
+            }
+        }
+
+        pub fn need_to_save(&self) -> bool {
+            self.save_data
+        }
+
+        pub fn saved(&mut self) {
+            self.save_data = false;
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct NvsTrendState {
+        styles: TrendStyles,
+    }
+
+    impl Storable for TrendMap {
+        fn store_tag(&self) -> &str {
+            "trend_styles"
+        }
+
+        fn store_data(&self) -> Vec<u8> {
+            let data = NvsTrendState {
+                styles: self.styles,
+            };
+
+            serde_json::to_string(&data).unwrap().into_bytes()
+        }
+
+        fn recall_data(&mut self, data: &[u8]) {
+            let nvs_state = serde_json::from_slice::<NvsTrendState>(data).unwrap();
+            self.styles = nvs_state.styles;
+            self.save_data = false;
+        }
+    }
+
+    impl ServableData for TrendMap {
+        fn get_channel(&mut self) -> mpsc::Sender<ServableDataReq> {
+            let (tx, rx) = mpsc::channel::<ServableDataReq>();
+            self.server_channel = Some(rx);
+            tx
+        }
+
+        fn handle_server_req(&mut self) {
+            if let Some(channel) = &self.server_channel {
+                if let Ok(req) = channel.try_recv() {
+                    info!("trend map got a request from server");
+
+                    if let ServableDataReq::Get(back_channel) = &req {
+                        info!("Sending trend styles to server");
+                        let mut rsp = ServerData::new();
+                        rsp.trend_styles = Some(self.styles);
+                        back_channel.send(ServableDataRsp::Data(rsp)).unwrap();
+                    }
+
+                    if let ServableDataReq::Set(update) = &req {
+                        if let Some(styles) = &update.trend_styles {
+                            self.styles = styles.merged_into(self.styles);
+                            self.save_data = true;
+                        }
+                    }
+
+                    if let ServableDataReq::Reset = &req {
+                        self.styles = TrendStyles::default();
+                        self.save_data = true;
+                    }
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::server::server::ServerUpdate;
+
+        #[test]
+        fn missing_trends_keep_their_defaults() {
+            let styles: TrendStyles =
+                serde_json::from_str(r#"{"flat": {"style": "pulse", "period": 2000}}"#).unwrap();
+
+            assert_eq!(styles.flat, TrendStyle::Pulse { period: 2000 });
+            assert_eq!(
+                TrendStyles {
+                    flat: TrendStyles::default().flat,
+                    ..styles
+                },
+                TrendStyles::default()
+            );
+        }
+
+        #[test]
+        fn trends_use_the_documented_names() {
+            let styles: TrendStyles = serde_json::from_str(
+                r#"{"double-up": {"style": "steady"}, "forty-five-down": {"style": "shift", "period": 500}}"#,
+            )
+            .unwrap();
+
+            assert_eq!(styles.double_up, TrendStyle::Steady);
+            assert_eq!(styles.forty_five_down, TrendStyle::Shift { period: 500 });
+            assert_eq!(styles.single_up, TrendStyles::default().single_up);
+        }
+
+        #[test]
+        fn posted_styles_only_change_what_was_sent() {
+            let mut trend_map = TrendMap::new();
+            let server = trend_map.get_channel();
+            let update = |json: &str| {
+                ServableDataReq::Set(ServerUpdate {
+                    trend_styles: Some(serde_json::from_str(json).unwrap()),
+                    ..ServerUpdate::default()
+                })
+            };
+
+            server
+                .send(update(r#"{"flat": {"style": "pulse", "period": 2000}}"#))
+                .unwrap();
+            trend_map.handle_server_req();
+            server
+                .send(update(r#"{"double-down": {"style": "steady"}}"#))
+                .unwrap();
+            trend_map.handle_server_req();
+
+            assert_eq!(
+                *trend_map.styles(),
+                TrendStyles {
+                    flat: TrendStyle::Pulse { period: 2000 },
+                    double_down: TrendStyle::Steady,
+                    ..TrendStyles::default()
+                }
+            );
+            assert!(trend_map.need_to_save());
+        }
+
+        #[test]
+        fn untrusted_trends_are_steady() {
+            let styles = TrendStyles::default();
+
+            assert_eq!(
+                styles.style(GlucoseTrend::NotComputable),
+                TrendStyle::Steady
+            );
+            assert_eq!(
+                styles.style(GlucoseTrend::RateOutOfRange),
+                TrendStyle::Steady
+            );
+            assert_eq!(styles.style(GlucoseTrend::DoubleUp), styles.double_up);
+        }
+    }
+}